@@ -0,0 +1,113 @@
+//! Reporting errors, lints and remarks, either for people to read or as JSON
+//! (one object per line on stderr) for tools such as editor plugins.
+
+use clap::ArgEnum;
+
+use crate::{intermediate::Span, json::Json};
+
+/// The format messages are reported in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+/// The severity of a message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Error,
+    Warning,
+    Remark,
+}
+
+/// A message about a program, with a code (such as the name of a lint) and a
+/// span in the source where it has one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic<'a> {
+    pub kind: Kind,
+    pub code: Option<&'a str>,
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Error => "error",
+            Kind::Warning => "warning",
+            Kind::Remark => "remark",
+        }
+    }
+}
+
+impl<'a> Diagnostic<'a> {
+    /// An error without a code or span, such as a file failing to open.
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic {
+            kind: Kind::Error,
+            code: None,
+            span: None,
+            message: message.into(),
+        }
+    }
+
+    /// Get the message as JSON, locating its span in the source.
+    pub fn to_json(&self, source: &str) -> Json {
+        Json::object(vec![
+            ("kind", self.kind.name().into()),
+            ("code", self.code.into()),
+            ("span", self.span.map(|span| span.to_json(source)).into()),
+            ("message", self.message.as_str().into()),
+        ])
+    }
+
+    /// Report the message, for a source (which may be empty for messages
+    /// without a span).
+    pub fn report(&self, source: &str, format: MessageFormat) {
+        match (format, self.code, self.span) {
+            (MessageFormat::Json, _, _) => eprintln!("{}", self.to_json(source)),
+            // errors without a code are printed as they always have been
+            (MessageFormat::Human, None, _) => println!("{}", self.message),
+            (MessageFormat::Human, Some(code), Some(span)) => {
+                let (line, col) = span.location(source);
+                eprintln!(
+                    "{}[{}] {}:{}: {}",
+                    self.kind.name(),
+                    code,
+                    line,
+                    col,
+                    self.message
+                )
+            }
+            (MessageFormat::Human, Some(code), None) => {
+                eprintln!("{}[{}]: {}", self.kind.name(), code, self.message)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_json_messages() {
+        let source = "+\n[]";
+        let lint = Diagnostic {
+            kind: Kind::Warning,
+            code: Some("infinite-loop"),
+            span: Some(Span { start: 2, end: 4 }),
+            message: "loop never ends".to_string(),
+        };
+        assert_eq!(
+            lint.to_json(source).to_string(),
+            r#"{"kind":"warning","code":"infinite-loop","span":{"start":2,"end":4,"line":2,"column":1},"message":"loop never ends"}"#
+        );
+        assert_eq!(
+            Diagnostic::error("Unable to open file")
+                .to_json("")
+                .to_string(),
+            r#"{"kind":"error","code":null,"span":null,"message":"Unable to open file"}"#
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Formatter for brainfuck source. Commands are reflowed and indented by loop
+//! depth, while comments and inserts are kept exactly as written.
+//!
+//! ## Layout
+//! - Runs of the same command are merged (whitespace between them is removed),
+//!   and optionally separated by spaces (e.g `+++ > --`).
+//! - Loops containing only commands are kept on one line if they fit,
+//!   otherwise the body is placed between `[` and `]` lines, indented.
+//! - Comments on the same line as the code before them stay there, all others
+//!   (and all inserts) are placed on their own line.
+//! - Blank lines separating sections of the source are kept (but collapsed to
+//!   a single blank line).
+
+use crate::syntax::{Node, Syntax};
+
+/// Options controlling the layout of formatted source.
+pub struct Style {
+    /// The maximum width of a line (comments and inserts may exceed this).
+    pub width: usize,
+    /// The number of spaces to indent by per loop depth.
+    pub indent: usize,
+    /// Separate runs of different commands with spaces.
+    pub group_runs: bool,
+}
+
+/// Format a brainfuck program.
+pub fn format(Syntax(nodes): &Syntax, style: &Style) -> String {
+    let mut printer = Printer {
+        style,
+        result: String::new(),
+        line: String::new(),
+        depth: 0,
+    };
+    printer.nodes(nodes);
+    printer.flush();
+    printer.result
+}
+
+/// The layout units of a sequence of syntax nodes.
+enum Item<'a> {
+    Run(char, usize),
+    Loop(&'a [Node]),
+    Insert(&'a str),
+    /// A comment, and if it trails code on the same line.
+    Comment(&'a str, bool),
+    BlankLine,
+}
+
+/// Group nodes into runs, and determine where comments and blank lines are.
+fn items(nodes: &[Node]) -> Vec<Item<'_>> {
+    let mut items = Vec::new();
+    let mut newline = false;
+    for node in nodes {
+        match node {
+            Node::Command(c) => match items.last_mut() {
+                Some(Item::Run(prev, n)) if prev == c => *n += 1,
+                _ => items.push(Item::Run(*c, 1)),
+            },
+            Node::Loop(nodes) => items.push(Item::Loop(nodes)),
+            Node::Insert(asm) => items.push(Item::Insert(asm)),
+            Node::Comment(comment) => items.push(Item::Comment(
+                comment,
+                !newline && matches!(items.last(), Some(Item::Run(..) | Item::Loop(_))),
+            )),
+            Node::Whitespace(space) => {
+                if space.matches('\n').count() > 1 && !items.is_empty() {
+                    items.push(Item::BlankLine)
+                }
+            }
+        }
+        newline = matches!(node, Node::Whitespace(space) if space.contains('\n'));
+    }
+    items
+}
+
+struct Printer<'a> {
+    style: &'a Style,
+    result: String,
+    line: String,
+    depth: usize,
+}
+
+impl<'a> Printer<'a> {
+    /// The width available on a line at the current depth.
+    fn available(&self) -> usize {
+        self.style
+            .width
+            .saturating_sub(self.style.indent * self.depth)
+            .max(1)
+    }
+
+    /// Write the current line (if any) to the result.
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            self.result += &" ".repeat(self.style.indent * self.depth);
+            self.result += &self.line;
+            self.result.push('\n');
+            self.line.clear();
+        }
+    }
+
+    /// Place a token on the current line, wrapping if it does not fit.
+    fn token(&mut self, token: &str) {
+        let sep = usize::from(self.style.group_runs && !self.line.is_empty());
+        if !self.line.is_empty() && self.line.len() + sep + token.len() > self.available() {
+            self.flush();
+        } else if sep == 1 {
+            self.line.push(' ');
+        }
+        self.line += token;
+    }
+
+    /// Place a token on a line of its own.
+    fn own_line(&mut self, token: &str) {
+        self.flush();
+        self.line += token;
+        self.flush();
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        for item in items(nodes) {
+            match item {
+                Item::Run(c, n) if self.style.group_runs => {
+                    let mut remaining = n;
+                    while remaining > 0 {
+                        let len = remaining.min(self.available());
+                        self.token(&c.to_string().repeat(len));
+                        remaining -= len;
+                    }
+                }
+                Item::Run(c, n) => {
+                    for _ in 0..n {
+                        self.token(&c.to_string());
+                    }
+                }
+                Item::Loop(nodes) => {
+                    let inline = nodes
+                        .iter()
+                        .map(|node| match node {
+                            Node::Command(c) => Some(c.to_string()),
+                            Node::Whitespace(_) => Some(String::new()),
+                            _ => None,
+                        })
+                        .collect::<Option<String>>()
+                        .map(|body| format!("[{}]", body))
+                        .filter(|inline| inline.len() <= self.available());
+                    if let Some(inline) = inline {
+                        self.token(&inline)
+                    } else {
+                        self.own_line("[");
+                        self.depth += 1;
+                        self.nodes(nodes);
+                        self.flush();
+                        self.depth -= 1;
+                        self.own_line("]");
+                    }
+                }
+                Item::Insert(asm) => self.own_line(&format!("::{}::", asm)),
+                Item::Comment(comment, true) if !self.line.is_empty() => {
+                    self.line += &format!(" #{}#", comment);
+                    self.flush();
+                }
+                Item::Comment(comment, _) => self.own_line(&format!("#{}#", comment)),
+                Item::BlankLine => {
+                    self.flush();
+                    if !self.result.is_empty() && !self.result.ends_with("\n\n") {
+                        self.result.push('\n');
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_syntax;
+
+    fn format_str(source: &str, width: usize, group_runs: bool) -> String {
+        format(
+            &parse_syntax(source).unwrap(),
+            &Style {
+                width,
+                indent: 2,
+                group_runs,
+            },
+        )
+    }
+
+    #[test]
+    fn groups_and_wraps_runs() {
+        assert_eq!(format_str("+ + +>>-", 80, true), "+++ >> -\n");
+        assert_eq!(format_str("+ + +>>-", 80, false), "+++>>-\n");
+        assert_eq!(format_str("+++>>-", 4, true), "+++\n>> -\n");
+        assert_eq!(format_str("++++++", 4, true), "++++\n++\n");
+        assert_eq!(format_str("+++>>-", 4, false), "+++>\n>-\n");
+    }
+
+    #[test]
+    fn indents_loops() {
+        assert_eq!(format_str("+[-]>", 80, true), "+ [-] >\n");
+        assert_eq!(
+            format_str("+[>[-]<-]>", 80, true),
+            "+\n[\n  > [-] < -\n]\n>\n"
+        );
+        assert_eq!(format_str("[->+<]", 4, false), "[\n  ->\n  +<\n]\n");
+    }
+
+    #[test]
+    fn preserves_comments_and_inserts() {
+        assert_eq!(
+            format_str(
+                "#header#\n+++ #add three#\n\n\n::mov r0, r1\n  ldr r1, [r0]::[.#print#-]",
+                80,
+                true
+            ),
+            "#header#\n+++ #add three#\n\n::mov r0, r1\n  ldr r1, [r0]::\n[\n  . #print#\n  -\n]\n"
+        );
+    }
+
+    #[test]
+    fn formatting_preserves_program() {
+        let source = "#sub# ,>,[<->-]<\n\n++++++++++++++++++++++++++++++++++++++++++++++++.";
+        let formatted = format_str(source, 10, true);
+        assert_eq!(
+            parse_syntax(&formatted).map(|syntax| crate::intermediate::BrainFuck::from(&syntax)),
+            Ok(crate::parser::parse(source).unwrap())
+        );
+        assert_eq!(format_str(&formatted, 10, true), formatted);
+    }
+}
@@ -0,0 +1,246 @@
+//! A control flow graph of basic blocks, lowered from the tree representation.
+//!
+//! Each block is a run of straight line statements (no `WhileNonZero`) ended
+//! by an explicit terminator. Loops are lowered with the test at both the
+//! entry and the end of the body, so `+[->+<].` becomes:
+//! ```text
+//! block 0:
+//!     +
+//!     branch 1, 2
+//! block 1:
+//!     ->+<
+//!     branch 1, 2
+//! block 2:
+//!     .
+//!     halt
+//! ```
+//! Blocks are numbered in program order, so a backend laying them out in
+//! order can fall through to the next block.
+
+use std::fmt::Display;
+
+use super::{BrainFuck, Stat, Stats};
+
+/// The index of a block in the graph.
+pub type BlockId = usize;
+
+/// How control leaves a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    /// Continue at the first block if the current cell is non-zero, otherwise
+    /// at the second.
+    Branch(BlockId, BlockId),
+    /// End the program.
+    Halt,
+}
+
+/// A run of statements without control flow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Stats,
+    pub terminator: Terminator,
+}
+
+/// The blocks of a program, starting at the first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg(pub Vec<Block>);
+
+/// Reasons a graph is malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The graph has no entry block.
+    Empty,
+    /// A block contains a loop, which should be lowered to branches.
+    NestedLoop(BlockId),
+    /// A terminator refers to a block that does not exist.
+    UnknownTarget(BlockId, BlockId),
+    /// A block cannot be reached from the entry.
+    Unreachable(BlockId),
+}
+
+impl Block {
+    fn new() -> Self {
+        Block {
+            stats: Vec::new(),
+            terminator: Terminator::Halt,
+        }
+    }
+}
+
+impl Terminator {
+    /// The blocks control can continue at.
+    pub fn targets(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Branch(nonzero, zero) => vec![*nonzero, *zero],
+            Terminator::Halt => vec![],
+        }
+    }
+}
+
+impl From<&BrainFuck> for Cfg {
+    fn from(BrainFuck(stats): &BrainFuck) -> Self {
+        let mut cfg = Cfg(vec![Block::new()]);
+        cfg.lower(stats, 0);
+        cfg
+    }
+}
+
+impl Cfg {
+    fn add_block(&mut self) -> BlockId {
+        self.0.push(Block::new());
+        self.0.len() - 1
+    }
+
+    /// Lower statements onto the end of a block, returning the block control
+    /// reaches after them.
+    fn lower(&mut self, stats: &[Stat], mut current: BlockId) -> BlockId {
+        for stat in stats {
+            match stat {
+                Stat::WhileNonZero(body) => {
+                    let start = self.add_block();
+                    let end = self.lower(body, start);
+                    let exit = self.add_block();
+                    self.0[current].terminator = Terminator::Branch(start, exit);
+                    self.0[end].terminator = Terminator::Branch(start, exit);
+                    current = exit;
+                }
+                stat => self.0[current].stats.push(stat.clone()),
+            }
+        }
+        current
+    }
+
+    /// Check the graph is well formed.
+    pub fn verify(&self) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return Err(Error::Empty);
+        }
+        for (id, block) in self.0.iter().enumerate() {
+            if block
+                .stats
+                .iter()
+                .any(|stat| matches!(stat, Stat::WhileNonZero(_)))
+            {
+                return Err(Error::NestedLoop(id));
+            }
+            if let Some(target) = block
+                .terminator
+                .targets()
+                .into_iter()
+                .find(|target| *target >= self.0.len())
+            {
+                return Err(Error::UnknownTarget(id, target));
+            }
+        }
+
+        let mut reached = vec![false; self.0.len()];
+        let mut pending = vec![0];
+        while let Some(id) = pending.pop() {
+            if !reached[id] {
+                reached[id] = true;
+                pending.extend(self.0[id].terminator.targets());
+            }
+        }
+        match reached.iter().position(|reached| !reached) {
+            Some(id) => Err(Error::Unreachable(id)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Display for Cfg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (id, block) in self.0.iter().enumerate() {
+            writeln!(f, "block {}:", id)?;
+            if !block.stats.is_empty() {
+                write!(f, "    ")?;
+                for stat in &block.stats {
+                    write!(f, "{}", stat)?;
+                }
+                writeln!(f)?;
+            }
+            match block.terminator {
+                Terminator::Branch(nonzero, zero) => {
+                    writeln!(f, "    branch {}, {}", nonzero, zero)?
+                }
+                Terminator::Halt => writeln!(f, "    halt")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Empty => write!(f, "no entry block"),
+            Error::NestedLoop(id) => write!(f, "block {} contains a loop", id),
+            Error::UnknownTarget(id, target) => {
+                write!(f, "block {} continues at unknown block {}", id, target)
+            }
+            Error::Unreachable(id) => write!(f, "block {} is unreachable", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn lowers_loops_to_branches() {
+        let cfg = Cfg::from(&parse("+[->+<].").unwrap());
+        assert_eq!(
+            cfg.to_string(),
+            "block 0:\n    +\n    branch 1, 2\nblock 1:\n    ->+<\n    branch 1, 2\n\
+             block 2:\n    .\n    halt\n"
+        );
+        assert_eq!(cfg.verify(), Ok(()));
+    }
+
+    #[test]
+    fn lowers_nested_loops() {
+        let cfg = Cfg::from(&parse("[[]>]").unwrap());
+        assert_eq!(
+            cfg.0
+                .iter()
+                .map(|block| block.terminator)
+                .collect::<Vec<_>>(),
+            vec![
+                Terminator::Branch(1, 4),
+                Terminator::Branch(2, 3),
+                Terminator::Branch(2, 3),
+                Terminator::Branch(1, 4),
+                Terminator::Halt
+            ]
+        );
+        assert_eq!(cfg.verify(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_malformed_graphs() {
+        let block = |stats, terminator| Block { stats, terminator };
+        assert_eq!(Cfg(vec![]).verify(), Err(Error::Empty));
+        assert_eq!(
+            Cfg(vec![block(
+                vec![Stat::WhileNonZero(vec![])],
+                Terminator::Halt
+            )])
+            .verify(),
+            Err(Error::NestedLoop(0))
+        );
+        assert_eq!(
+            Cfg(vec![block(vec![], Terminator::Branch(0, 1))]).verify(),
+            Err(Error::UnknownTarget(0, 1))
+        );
+        assert_eq!(
+            Cfg(vec![
+                block(vec![], Terminator::Halt),
+                block(vec![], Terminator::Branch(1, 0))
+            ])
+            .verify(),
+            Err(Error::Unreachable(1))
+        );
+    }
+}
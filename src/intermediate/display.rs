@@ -2,10 +2,7 @@
 //! of the brainfuck language.
 
 use super::{BrainFuck, Op, Stat};
-use std::{
-    fmt::Display,
-    i32::{MAX, MIN},
-};
+use std::fmt::Display;
 
 impl Display for BrainFuck {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -38,8 +35,8 @@ impl Display for Stat {
                 1 => write!(f, ">"),
                 0 => write!(f, ""),
                 -1 => write!(f, "<"),
-                i @ MIN..=-2 => write!(f, "<({})", i),
-                i @ 2..=MAX => write!(f, ">({})", i),
+                i @ i32::MIN..=-2 => write!(f, "<({})", i),
+                i @ 2..=i32::MAX => write!(f, ">({})", i),
             },
             Stat::DerefOp(Op::Add, 1) => write!(f, "+"),
             Stat::DerefOp(Op::Add, -1) => write!(f, "-"),
@@ -53,7 +50,33 @@ impl Display for Stat {
                 }
                 write!(f, "]")
             }
+            Stat::SetCells(offset, cells) => write!(
+                f,
+                "=({}){{{}}}",
+                offset,
+                cells
+                    .iter()
+                    .map(|cell| cell.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Stat::OutputConst(bytes) => write!(f, "\"{}\"", bytes.escape_ascii()),
+            Stat::ScanZero(stride) => write!(f, "?({})", stride),
+            Stat::MulAdd(offset, factor) => write!(f, "+=({},{})", offset, factor),
             Stat::Asm(asm) => write!(f, "::{}::", asm),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_constant_output() {
+        assert_eq!(
+            Stat::OutputConst(b"Hi \"bf\"\n\0".to_vec()).to_string(),
+            "\"Hi \\\"bf\\\"\\n\\x00\""
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Serialising the intermediate representation (with spans) as JSON, for tools
+//! that should not parse the displayed form.
+//!
+//! A program is an object with a `stats` array. Each statement is an object
+//! with its `kind` (the variant name), its fields, and its `span` in the
+//! source. Loops have their statements in `body`:
+//! ```text
+//! {"stats":[{"kind":"Input","span":{...}},{"kind":"WhileNonZero","body":[...],"span":{...}}]}
+//! ```
+
+use super::{BrainFuck, Op, Span, SpanTree, Stat};
+use crate::json::Json;
+
+impl Span {
+    /// Get the span as JSON, with its line and column in the source.
+    pub fn to_json(self, source: &str) -> Json {
+        let (line, column) = self.location(source);
+        Json::object(vec![
+            ("start", self.start.into()),
+            ("end", self.end.into()),
+            ("line", line.into()),
+            ("column", column.into()),
+        ])
+    }
+}
+
+impl Op {
+    fn name(&self) -> &'static str {
+        match self {
+            Op::Add => "Add",
+            Op::Mul => "Mul",
+            Op::Div => "Div",
+            Op::Mod => "Mod",
+        }
+    }
+}
+
+fn bytes(bytes: &[u8]) -> Json {
+    Json::Array(bytes.iter().map(|b| (*b).into()).collect())
+}
+
+/// Get a statement (with its span) as JSON.
+fn stat_json(stat: &Stat, SpanTree(span, inner): &SpanTree, source: &str) -> Json {
+    let (kind, mut fields): (&str, Vec<(&'static str, Json)>) = match stat {
+        Stat::PtrMove(i) => ("PtrMove", vec![("amount", (*i).into())]),
+        Stat::DerefOp(op, i) => (
+            "DerefOp",
+            vec![("op", op.name().into()), ("amount", (*i).into())],
+        ),
+        Stat::Output => ("Output", vec![]),
+        Stat::Input => ("Input", vec![]),
+        Stat::WhileNonZero(body) => (
+            "WhileNonZero",
+            vec![(
+                "body",
+                Json::Array(
+                    body.iter()
+                        .zip(inner)
+                        .map(|(stat, span)| stat_json(stat, span, source))
+                        .collect(),
+                ),
+            )],
+        ),
+        Stat::SetCells(offset, cells) => (
+            "SetCells",
+            vec![("offset", (*offset).into()), ("values", bytes(cells))],
+        ),
+        Stat::OutputConst(output) => ("OutputConst", vec![("bytes", bytes(output))]),
+        Stat::ScanZero(stride) => ("ScanZero", vec![("stride", (*stride).into())]),
+        Stat::MulAdd(offset, factor) => (
+            "MulAdd",
+            vec![("offset", (*offset).into()), ("factor", (*factor).into())],
+        ),
+        Stat::Asm(code) => ("Asm", vec![("code", code.as_str().into())]),
+    };
+    fields.insert(0, ("kind", kind.into()));
+    fields.push(("span", span.to_json(source)));
+    Json::object(fields)
+}
+
+impl BrainFuck {
+    /// Get the program as JSON, given the spans of its statements in a source.
+    pub fn to_json(&self, spans: &[SpanTree], source: &str) -> Json {
+        Json::object(vec![(
+            "stats",
+            Json::Array(
+                self.0
+                    .iter()
+                    .zip(spans)
+                    .map(|(stat, span)| stat_json(stat, span, source))
+                    .collect(),
+            ),
+        )])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_spanned;
+
+    #[test]
+    fn serialises_nested_loops_with_spans() {
+        let source = ",\n[->+<]";
+        let (bf, spans) = parse_spanned(source).unwrap();
+        let json = bf.to_json(&spans, source).to_string();
+        assert!(json.starts_with(
+            r#"{"stats":[{"kind":"Input","span":{"start":0,"end":1,"line":1,"column":1}},{"kind":"WhileNonZero","body":[{"kind":"DerefOp","op":"Add","amount":-1,"span":{"start":3,"end":4,"line":2,"column":2}},"#
+        ));
+        assert!(json.ends_with(r#""span":{"start":2,"end":8,"line":2,"column":1}}]}"#));
+    }
+}
@@ -1,7 +1,9 @@
 //! The first brainfuck representation. It is extended over the grammar to allow
 //! for optimisations and makes use of vectors to allow for slice pattern matches.
 
+pub mod cfg;
 pub mod display;
+pub mod json;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct BrainFuck(pub Stats);
@@ -26,5 +28,42 @@ pub enum Stat {
     Output,
     Input,
     WhileNonZero(Stats),
+    /// Set the cells starting at an offset from the pointer.
+    SetCells(i32, Vec<u8>),
+    /// Output known bytes, leaving the tape unchanged.
+    OutputConst(Vec<u8>),
+    /// Move the pointer by a stride until it reaches a zero cell (a loop of
+    /// only pointer moves, such as `[>]`).
+    ScanZero(i32),
+    /// Add the current cell multiplied by a factor to the cell at an offset
+    /// (a strength reduced loop, such as `[->++<]`). Like the loop, the target
+    /// is left alone when the current cell is zero.
+    MulAdd(i32, i32),
     Asm(String),
 }
+
+/// The location of a statement in the source, as byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The span of a statement, with the spans of the statements in its body (for
+/// while loops).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanTree(pub Span, pub Spans);
+
+/// The spans of a sequence of statements, mirroring the structure of [Stats].
+pub type Spans = Vec<SpanTree>;
+
+impl Span {
+    /// Get the line and column (both from 1) of the start of the span.
+    pub fn location(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start];
+        (
+            before.matches('\n').count() + 1,
+            before.chars().rev().take_while(|c| *c != '\n').count() + 1,
+        )
+    }
+}
@@ -0,0 +1,307 @@
+//! Interpreter for the intermediate representation.
+//!
+//! Cells are unsigned bytes that wrap on overflow. On end of input the cell is
+//! set to 255 (matching `getchar` returning `EOF` in the C99 backend). Output
+//! is flushed before reading input, so buffered writers can be used.
+//!
+//! For untrusted programs the statements run, the time taken and the bytes
+//! output can be limited. The time limit is checked periodically while
+//! running, so cannot interrupt a program waiting for input.
+
+use std::{
+    io::{Read, Write},
+    time::Instant,
+};
+
+use crate::intermediate::{Op, Stat, Stats};
+
+/// The state of the brainfuck machine.
+#[derive(Debug, Clone)]
+pub struct Machine {
+    tape: Vec<u8>,
+    ptr: usize,
+    start: usize,
+    /// The number of statements that can still be run, if limited.
+    pub steps: Option<u64>,
+    /// The time to stop running by, if limited.
+    pub deadline: Option<Instant>,
+    /// The number of bytes that can still be output, if limited.
+    pub output_limit: Option<u64>,
+    /// The number of statements run (wrapping), to check the deadline
+    /// periodically.
+    ticks: u32,
+}
+
+/// The number of statements run between checks of the deadline.
+const DEADLINE_INTERVAL: u32 = 1 << 12;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The pointer was moved outside of the tape.
+    PointerOutOfBounds,
+    /// Inserts are target specific, so cannot be interpreted.
+    Insert,
+    /// The limit on statements run was reached.
+    StepLimit,
+    /// The deadline passed.
+    Timeout,
+    /// The limit on bytes output was reached.
+    OutputLimit,
+    /// Reading input or writing output failed.
+    Io,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Error::PointerOutOfBounds => "The pointer moved outside of the tape",
+                Error::Insert => "Inserts cannot be interpreted",
+                Error::StepLimit => "The step limit was reached",
+                Error::Timeout => "The time limit was reached",
+                Error::OutputLimit => "The output limit was reached",
+                Error::Io => "Reading input or writing output failed",
+            }
+        )
+    }
+}
+
+/// A writer flushing after every write, for interactive programs.
+pub struct Unbuffered<W: Write>(pub W);
+
+impl<W: Write> Write for Unbuffered<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.0.write(buf)?;
+        self.0.flush()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Machine {
+    /// Create a machine with pre cells before the initial pointer position,
+    /// and post cells after (including the initial cell).
+    pub fn new(pre: u32, post: u32) -> Self {
+        Machine {
+            tape: vec![0; (pre + post) as usize],
+            ptr: pre as usize,
+            start: pre as usize,
+            steps: None,
+            deadline: None,
+            output_limit: None,
+            ticks: 0,
+        }
+    }
+
+    /// The offset of the pointer from its initial position.
+    pub fn ptr(&self) -> i32 {
+        self.ptr as i32 - self.start as i32
+    }
+
+    /// The cells of the tape, and the index of the initial pointer position.
+    pub fn tape(&self) -> (&[u8], usize) {
+        (&self.tape, self.start)
+    }
+
+    /// Get the index of the cell at an offset from the pointer.
+    fn index(&self, offset: i32) -> Result<usize, Error> {
+        usize::try_from(self.ptr as i64 + i64::from(offset))
+            .ok()
+            .filter(|index| *index < self.tape.len())
+            .ok_or(Error::PointerOutOfBounds)
+    }
+
+    /// Run statements, stopping at the first error.
+    pub fn run(
+        &mut self,
+        stats: &Stats,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<(), Error> {
+        for stat in stats {
+            self.step(stat, input, output)?;
+        }
+        Ok(())
+    }
+
+    /// Use up a step, if steps are limited, and check the deadline.
+    fn tick(&mut self) -> Result<(), Error> {
+        if let Some(steps) = &mut self.steps {
+            *steps = steps.checked_sub(1).ok_or(Error::StepLimit)?;
+        }
+        if let Some(deadline) = self.deadline {
+            self.ticks = self.ticks.wrapping_add(1);
+            if self.ticks.is_multiple_of(DEADLINE_INTERVAL) && Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write bytes, up to the output limit.
+    fn output(&mut self, bytes: &[u8], output: &mut dyn Write) -> Result<(), Error> {
+        let allowed = match &mut self.output_limit {
+            Some(limit) => {
+                let allowed = bytes.len().min(*limit as usize);
+                *limit -= allowed as u64;
+                allowed
+            }
+            None => bytes.len(),
+        };
+        output.write_all(&bytes[..allowed]).map_err(|_| Error::Io)?;
+        if allowed < bytes.len() {
+            Err(Error::OutputLimit)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run a single statement.
+    pub fn step(
+        &mut self,
+        stat: &Stat,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<(), Error> {
+        self.tick()?;
+        match stat {
+            Stat::PtrMove(i) => self.ptr = self.index(*i)?,
+            Stat::DerefOp(op, i) => {
+                let (cell, i) = (&mut self.tape[self.ptr], i.rem_euclid(256) as u8);
+                *cell = match op {
+                    Op::Add => cell.wrapping_add(i),
+                    Op::Mul => cell.wrapping_mul(i),
+                    Op::Div => cell.checked_div(i).unwrap_or(0),
+                    Op::Mod => cell.checked_rem(i).unwrap_or(0),
+                }
+            }
+            Stat::Output => self.output(&[self.tape[self.ptr]], output)?,
+            Stat::OutputConst(bytes) => self.output(bytes, output)?,
+            Stat::Input => {
+                output.flush().map_err(|_| Error::Io)?;
+                let mut byte = [255];
+                input.read(&mut byte).map_err(|_| Error::Io)?;
+                self.tape[self.ptr] = byte[0];
+            }
+            Stat::WhileNonZero(stats) => {
+                while self.tape[self.ptr] != 0 {
+                    self.run(stats, input, output)?;
+                    self.tick()?;
+                }
+            }
+            Stat::ScanZero(stride) => {
+                while self.tape[self.ptr] != 0 {
+                    self.ptr = self.index(*stride)?;
+                    self.tick()?;
+                }
+            }
+            // the target is only accessed by the loop when the cell is non-zero
+            Stat::MulAdd(_, _) if self.tape[self.ptr] == 0 => (),
+            Stat::MulAdd(offset, factor) => {
+                let (target, factor) = (self.index(*offset)?, factor.rem_euclid(256) as u8);
+                self.tape[target] =
+                    self.tape[target].wrapping_add(self.tape[self.ptr].wrapping_mul(factor))
+            }
+            Stat::SetCells(offset, cells) => {
+                let start = self.index(*offset)?;
+                self.tape
+                    .get_mut(start..start + cells.len())
+                    .ok_or(Error::PointerOutOfBounds)?
+                    .copy_from_slice(cells)
+            }
+            Stat::Asm(_) => return Err(Error::Insert),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{intermediate::BrainFuck, parser::parse};
+
+    fn run(source: &str, input: &[u8]) -> (Result<(), Error>, Vec<u8>) {
+        let BrainFuck(stats) = parse(source).unwrap();
+        let mut machine = Machine::new(0, 100);
+        machine.steps = Some(10_000);
+        let mut output = Vec::new();
+        (machine.run(&stats, &mut &input[..], &mut output), output)
+    }
+
+    #[test]
+    fn runs_programs() {
+        assert_eq!(
+            run(
+                ",>,[<->-]<++++++++++++++++++++++++++++++++++++++++++++++++.",
+                b"da"
+            ),
+            (Ok(()), b"3".to_vec())
+        );
+        assert_eq!(run("-.,.", b""), (Ok(()), vec![255, 255]));
+    }
+
+    #[test]
+    fn stops_on_errors() {
+        assert_eq!(run("+.<", b""), (Err(Error::PointerOutOfBounds), vec![1]));
+        assert_eq!(run("+[]", b""), (Err(Error::StepLimit), vec![]));
+        assert_eq!(run("::nop::", b""), (Err(Error::Insert), vec![]));
+    }
+
+    #[test]
+    fn skips_multiply_adds_on_zero_cells() {
+        // the loop is never entered, so the cell before the tape is untouched
+        let (bf, spans) = crate::parser::parse_spanned(",[-<+>],[.,]").unwrap();
+        let (BrainFuck(stats), _) =
+            crate::optimiser::optimise(bf, spans, Some((0, 10)), &[], &mut |_, _, _| ());
+        assert!(stats.contains(&Stat::MulAdd(-1, 1)));
+        let mut machine = Machine::new(0, 10);
+        let mut output = Vec::new();
+        let result = machine.run(&stats, &mut &b"\0\0"[..], &mut output);
+        assert_eq!((result, output), (Ok(()), vec![]));
+    }
+
+    #[test]
+    fn counts_steps_not_run_at_compile_time() {
+        let run_limited = |tape| {
+            let (bf, spans) = crate::parser::parse_spanned("++++++++[>++++++++<-]>+.").unwrap();
+            let (BrainFuck(stats), _) =
+                crate::optimiser::optimise(bf, spans, tape, &[], &mut |_, _, _| ());
+            let mut machine = Machine::new(0, 10);
+            machine.steps = Some(5);
+            let mut output = Vec::new();
+            (machine.run(&stats, &mut &b""[..], &mut output), output)
+        };
+        // folded into constant output, so the loop is run without using steps
+        assert_eq!(run_limited(Some((0, 10))), (Ok(()), b"A".to_vec()));
+        assert_eq!(run_limited(None), (Err(Error::StepLimit), vec![]));
+    }
+
+    #[test]
+    fn stops_at_limits() {
+        let BrainFuck(stats) = parse("+[.]").unwrap();
+        let mut machine = Machine::new(0, 1);
+        machine.output_limit = Some(3);
+        let mut output = Vec::new();
+        let result = machine.run(&stats, &mut &b""[..], &mut output);
+        assert_eq!((result, output), (Err(Error::OutputLimit), vec![1; 3]));
+
+        let mut machine = Machine::new(0, 1);
+        machine.deadline = Some(Instant::now());
+        let result = machine.run(&stats, &mut &b""[..], &mut std::io::sink());
+        assert_eq!(result, Err(Error::Timeout));
+    }
+
+    #[test]
+    fn flushes_before_input() {
+        let BrainFuck(stats) = parse("+.,").unwrap();
+        let mut output = std::io::BufWriter::new(Vec::new());
+        let mut machine = Machine::new(0, 1);
+        machine.run(&stats, &mut &b"a"[..], &mut output).unwrap();
+        assert_eq!(output.get_ref(), &vec![1]);
+    }
+}
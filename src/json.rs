@@ -0,0 +1,284 @@
+//! A minimal JSON value, written compactly for tools consuming the compiler's
+//! output, and parsed from the messages of tools talking to the compiler.
+
+use std::{
+    fmt::{Display, Formatter, Result},
+    str::CharIndices,
+};
+
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, multispace0},
+    combinator::{map, value},
+    multi::separated_list0,
+    number::complete::double,
+    sequence::{delimited, separated_pair},
+    IResult,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    /// Fields are kept in the order given.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Get a field of an object.
+    pub fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+macro_rules! from_number {
+    ($($t:ty),*) => {$(
+        impl From<$t> for Json {
+            fn from(n: $t) -> Self {
+                Json::Number(n as i64)
+            }
+        }
+    )*};
+}
+
+from_number!(i32, u8, usize);
+
+/// Write a string with quotes, escaping characters JSON requires.
+fn write_string(s: &str, f: &mut Formatter<'_>) -> Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Parse the 4 hex digits of a UTF-16 code unit in a `\\u` escape.
+fn code_unit(chars: &mut CharIndices) -> Option<u16> {
+    let hex: String = chars.take(4).map(|(_, c)| c).collect();
+    u16::from_str_radix(&hex, 16)
+        .ok()
+        .filter(|_| hex.len() == 4)
+}
+
+/// Parse the contents of a string (after the opening quote), up to and
+/// including the closing quote.
+fn string_contents(input: &str) -> IResult<&str, String> {
+    let error = || nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char));
+    let mut result = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((&input[i + 1..], result)),
+            '\\' => match chars.next().ok_or_else(error)?.1 {
+                'n' => result.push('\n'),
+                'r' => result.push('\r'),
+                't' => result.push('\t'),
+                'b' => result.push('\u{8}'),
+                'f' => result.push('\u{c}'),
+                'u' => {
+                    let first = code_unit(&mut chars).ok_or_else(error)?;
+                    // characters outside the basic plane are surrogate pairs
+                    let units = if (0xd800..0xdc00).contains(&first) {
+                        match (chars.next(), chars.next()) {
+                            (Some((_, '\\')), Some((_, 'u'))) => {
+                                vec![first, code_unit(&mut chars).ok_or_else(error)?]
+                            }
+                            _ => return Err(error()),
+                        }
+                    } else {
+                        vec![first]
+                    };
+                    result.extend(char::decode_utf16(units).map(|c| c.unwrap_or('\u{fffd}')))
+                }
+                c => result.push(c),
+            },
+            c => result.push(c),
+        }
+    }
+    Err(error())
+}
+
+fn string(input: &str) -> IResult<&str, String> {
+    let (input, _) = char('"')(input)?;
+    string_contents(input)
+}
+
+fn json(input: &str) -> IResult<&str, Json> {
+    delimited(
+        multispace0,
+        alt((
+            value(Json::Null, tag("null")),
+            value(Json::Bool(true), tag("true")),
+            value(Json::Bool(false), tag("false")),
+            map(double, |n| Json::Number(n as i64)),
+            map(string, Json::String),
+            map(
+                delimited(
+                    char('['),
+                    separated_list0(char(','), json),
+                    preceded_space(char(']')),
+                ),
+                Json::Array,
+            ),
+            map(
+                delimited(
+                    char('{'),
+                    separated_list0(
+                        char(','),
+                        separated_pair(
+                            delimited(multispace0, string, multispace0),
+                            char(':'),
+                            json,
+                        ),
+                    ),
+                    preceded_space(char('}')),
+                ),
+                Json::Object,
+            ),
+        )),
+        multispace0,
+    )(input)
+}
+
+/// Allow whitespace before a parser (for closing empty arrays and objects).
+fn preceded_space<'a, O>(
+    parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    nom::sequence::preceded(multispace0, parser)
+}
+
+/// Parse a JSON value, with nothing following it. Numbers are truncated to
+/// integers.
+pub fn parse(input: &str) -> Option<Json> {
+    match json(input) {
+        Ok(("", value)) => Some(value),
+        _ => None,
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(s, f),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(name, f)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_compact_json() {
+        let value = Json::object([
+            ("name", "a \"b\"\n\u{1}".into()),
+            ("values", Json::Array(vec![1.into(), Json::Null])),
+            ("empty", Json::object([])),
+            ("flag", Json::Bool(true)),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"a \"b\"\n\u0001","values":[1,null],"empty":{},"flag":true}"#
+        );
+    }
+
+    #[test]
+    fn parses_json() {
+        let value = Json::object([
+            ("name", "a \"b\"\n\u{1}\u{1f600}".into()),
+            (
+                "values",
+                Json::Array(vec![1.into(), Json::Null, (-2).into()]),
+            ),
+            ("empty", Json::object([])),
+            ("list", Json::Array(vec![])),
+        ]);
+        assert_eq!(parse(&value.to_string()), Some(value.clone()));
+        assert_eq!(
+            parse(
+                r#" { "name" : "a \"b\"\n\u0001\ud83d\ude00", "values": [1, null, -2.0],
+                "empty": { }, "list": [ ] } "#
+            ),
+            Some(value)
+        );
+        assert_eq!(parse(r#"{"a": 1"#), None);
+        assert_eq!(parse(r#""\u12""#), None);
+    }
+}
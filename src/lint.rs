@@ -0,0 +1,361 @@
+//! Static analysis warnings on the (unoptimised) intermediate representation.
+//!
+//! The program is abstractly run from the initial (zeroed) tape, tracking the
+//! pointer position and cell values while they are known. Each lint only
+//! reports behaviour that is certain given this knowledge.
+//!
+//! | Lint               | Warns about                                                  |
+//! |--------------------|--------------------------------------------------------------|
+//! | `infinite-loop`    | Loops entered on a non-zero cell that never change the cell  |
+//! | `unreachable-code` | Statements after a provably infinite loop                    |
+//! | `pointer-underflow`| The pointer moving below the start of the tape               |
+//! | `unbalanced-loop`  | Loops that move the pointer (excluding `[>]` style searches) |
+
+use std::collections::{HashMap, HashSet};
+
+use clap::ArgEnum;
+
+use crate::intermediate::{BrainFuck, Op, Span, SpanTree, Stat, Stats};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ArgEnum)]
+pub enum Lint {
+    InfiniteLoop,
+    UnreachableCode,
+    PointerUnderflow,
+    UnbalancedLoop,
+}
+
+/// How a lint is reported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// A lint triggered at some span in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub span: Span,
+    pub message: String,
+}
+
+impl Lint {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::InfiniteLoop => "infinite-loop",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::PointerUnderflow => "pointer-underflow",
+            Lint::UnbalancedLoop => "unbalanced-loop",
+        }
+    }
+
+    /// Get the level of the lint, deny taking precedence over allow.
+    pub fn level(&self, allowed: &[Lint], denied: &[Lint]) -> Level {
+        if denied.contains(self) {
+            Level::Deny
+        } else if allowed.contains(self) {
+            Level::Allow
+        } else {
+            Level::Warn
+        }
+    }
+}
+
+/// Run all lints over a program, given the number of cells before the initial
+/// pointer position.
+pub fn lint(BrainFuck(stats): &BrainFuck, spans: &[SpanTree], pre: u32) -> Vec<Warning> {
+    let mut linter = Linter {
+        pre: i64::from(pre),
+        warnings: Vec::new(),
+    };
+    linter.stats(stats, spans, &mut State::new(), true);
+    linter.warnings
+}
+
+/// The abstract state of the machine.
+#[derive(Clone)]
+struct State {
+    /// The offset of the pointer from its initial position, if known.
+    ptr: Option<i64>,
+    /// Cells (by offset) that have been changed, and their value if known.
+    cells: HashMap<i64, Option<u8>>,
+    /// If the cells not present in cells are still zero.
+    zeroed: bool,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            ptr: Some(0),
+            cells: HashMap::new(),
+            zeroed: true,
+        }
+    }
+
+    /// Forget everything known about the tape and pointer.
+    fn havoc(&mut self) {
+        *self = State {
+            ptr: None,
+            cells: HashMap::new(),
+            zeroed: false,
+        }
+    }
+
+    /// Get the value of the cell at an offset from the pointer, if known.
+    fn get(&self, offset: i32) -> Option<u8> {
+        let ptr = self.ptr? + i64::from(offset);
+        match self.cells.get(&ptr) {
+            Some(value) => *value,
+            None if self.zeroed => Some(0),
+            None => None,
+        }
+    }
+
+    /// Set the value of the cell at an offset from the pointer.
+    fn set(&mut self, offset: i32, value: Option<u8>) {
+        if let Some(ptr) = self.ptr {
+            self.cells.insert(ptr + i64::from(offset), value);
+        }
+    }
+}
+
+/// Get the net pointer movement of some statements, if it is known.
+fn net_move(stats: &Stats) -> Option<i32> {
+    stats.iter().try_fold(0, |total, stat| match stat {
+        Stat::PtrMove(i) => Some(total + i),
+        Stat::WhileNonZero(body) if net_move(body) == Some(0) => Some(total),
+        Stat::WhileNonZero(_) | Stat::ScanZero(_) | Stat::Asm(_) => None,
+        _ => Some(total),
+    })
+}
+
+/// Get the offsets of cells (relative to the pointer at the start) that some
+/// statements may write to, if known.
+fn writes(stats: &Stats) -> Option<HashSet<i32>> {
+    let mut offset = 0;
+    let mut written = HashSet::new();
+    for stat in stats {
+        match stat {
+            Stat::PtrMove(i) => offset += i,
+            Stat::DerefOp(_, _) | Stat::Input => {
+                written.insert(offset);
+            }
+            Stat::Output | Stat::OutputConst(_) => (),
+            Stat::MulAdd(target, _) => {
+                written.insert(offset + target);
+            }
+            Stat::SetCells(start, cells) => {
+                written.extend((0..cells.len() as i32).map(|i| offset + start + i))
+            }
+            Stat::WhileNonZero(body) if net_move(body) == Some(0) => {
+                written.extend(writes(body)?.into_iter().map(|i| i + offset))
+            }
+            Stat::WhileNonZero(_) | Stat::ScanZero(_) | Stat::Asm(_) => return None,
+        }
+    }
+    Some(written)
+}
+
+fn apply(op: &Op, value: u8, i: i32) -> Option<u8> {
+    let (value, i) = (i32::from(value), i.rem_euclid(256));
+    match op {
+        Op::Add => Some((value + i) as u8),
+        Op::Mul => Some((value * i) as u8),
+        Op::Div if i != 0 => Some((value / i) as u8),
+        Op::Mod if i != 0 => Some((value % i) as u8),
+        Op::Div | Op::Mod => None,
+    }
+}
+
+struct Linter {
+    pre: i64,
+    warnings: Vec<Warning>,
+}
+
+impl Linter {
+    fn warn(&mut self, lint: Lint, span: Span, message: &str) {
+        self.warnings.push(Warning {
+            lint,
+            span,
+            message: message.to_string(),
+        })
+    }
+
+    /// Lint a sequence of statements, certain is true if the statements are
+    /// always run. Returns true if the statements never terminate.
+    fn stats(
+        &mut self,
+        stats: &Stats,
+        spans: &[SpanTree],
+        state: &mut State,
+        certain: bool,
+    ) -> bool {
+        for (i, (stat, SpanTree(span, inner))) in stats.iter().zip(spans).enumerate() {
+            if self.stat(stat, *span, inner, state, certain) {
+                if let (Some(SpanTree(next, _)), Some(SpanTree(last, _))) =
+                    (spans.get(i + 1), spans.last())
+                {
+                    self.warn(
+                        Lint::UnreachableCode,
+                        Span {
+                            start: next.start,
+                            end: last.end,
+                        },
+                        "unreachable code after a loop that never terminates",
+                    )
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Lint a statement, returning true if it never terminates.
+    fn stat(
+        &mut self,
+        stat: &Stat,
+        span: Span,
+        inner: &[SpanTree],
+        state: &mut State,
+        certain: bool,
+    ) -> bool {
+        match stat {
+            Stat::PtrMove(i) => {
+                if let Some(ptr) = state.ptr {
+                    state.ptr = Some(ptr + i64::from(*i));
+                    if certain && ptr >= -self.pre && ptr + i64::from(*i) < -self.pre {
+                        self.warn(
+                            Lint::PointerUnderflow,
+                            span,
+                            "pointer moves below the start of the tape",
+                        )
+                    }
+                }
+            }
+            Stat::DerefOp(op, i) => state.set(0, state.get(0).and_then(|v| apply(op, v, *i))),
+            Stat::Input => state.set(0, None),
+            Stat::Output | Stat::OutputConst(_) => (),
+            Stat::MulAdd(offset, factor) => state.set(
+                *offset,
+                state
+                    .get(0)
+                    .zip(state.get(*offset))
+                    .and_then(|(v, t)| apply(&Op::Add, t, i32::from(v) * factor)),
+            ),
+            Stat::SetCells(offset, cells) => {
+                for (i, cell) in cells.iter().enumerate() {
+                    state.set(offset + i as i32, Some(*cell))
+                }
+            }
+            Stat::Asm(_) => state.havoc(),
+            Stat::ScanZero(_) if state.get(0) == Some(0) => (),
+            // stops at an unknown position
+            Stat::ScanZero(_) => state.havoc(),
+            Stat::WhileNonZero(body) => {
+                let entry = state.get(0);
+                let entered = matches!(entry, Some(v) if v != 0);
+                let delta = net_move(body);
+                let written = delta.filter(|d| *d == 0).and_then(|_| writes(body));
+
+                if matches!(delta, Some(d) if d != 0)
+                    && !body.iter().all(|stat| matches!(stat, Stat::PtrMove(_)))
+                {
+                    self.warn(
+                        Lint::UnbalancedLoop,
+                        span,
+                        "loop body moves the pointer, so each iteration uses different cells",
+                    )
+                }
+
+                let infinite = entered && matches!(&written, Some(cells) if !cells.contains(&0));
+
+                // the state at the start of any iteration of the body
+                let mut body_state = state.clone();
+                match &written {
+                    Some(cells) => {
+                        for offset in cells {
+                            body_state.set(*offset, None)
+                        }
+                    }
+                    None => body_state.havoc(),
+                }
+                let body_diverges = self.stats(body, inner, &mut body_state, certain && entered);
+
+                if infinite {
+                    self.warn(
+                        Lint::InfiniteLoop,
+                        span,
+                        "loop is entered on a non-zero cell that it never changes",
+                    )
+                }
+                if infinite || (entered && body_diverges) {
+                    return true;
+                }
+
+                if entry != Some(0) {
+                    match &written {
+                        Some(cells) => {
+                            for offset in cells {
+                                state.set(*offset, None)
+                            }
+                            state.set(0, Some(0))
+                        }
+                        None => state.havoc(),
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_spanned;
+
+    fn lints(source: &str, pre: u32) -> Vec<(Lint, usize, usize)> {
+        let (bf, spans) = parse_spanned(source).unwrap();
+        lint(&bf, &spans, pre)
+            .into_iter()
+            .map(|Warning { lint, span, .. }| (lint, span.start, span.end))
+            .collect()
+    }
+
+    #[test]
+    fn detects_infinite_loops() {
+        assert_eq!(
+            lints("+[]>+", 0),
+            vec![(Lint::InfiniteLoop, 1, 3), (Lint::UnreachableCode, 3, 5)]
+        );
+        assert_eq!(lints("+[>+<]", 0), vec![(Lint::InfiniteLoop, 1, 6)]);
+        assert_eq!(lints("[]+[-]", 0), vec![]);
+        assert_eq!(lints(",[]", 0), vec![]);
+        assert_eq!(
+            lints("+[[]]-", 0),
+            vec![
+                (Lint::InfiniteLoop, 2, 4),
+                (Lint::InfiniteLoop, 1, 5),
+                (Lint::UnreachableCode, 5, 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_pointer_underflow() {
+        assert_eq!(lints("><<<", 0), vec![(Lint::PointerUnderflow, 2, 3)]);
+        assert_eq!(lints("><<<", 2), vec![]);
+        assert_eq!(lints(",[<]<", 0), vec![]);
+        assert_eq!(lints("+[<+>-]", 0), vec![(Lint::PointerUnderflow, 2, 3)]);
+        assert_eq!(lints("[<+>-]", 0), vec![]);
+    }
+
+    #[test]
+    fn detects_unbalanced_loops() {
+        assert_eq!(lints(",[>]", 0), vec![]);
+        assert_eq!(lints(",[>,]", 0), vec![(Lint::UnbalancedLoop, 1, 5)]);
+        assert_eq!(lints(",[->+<]", 0), vec![]);
+    }
+}
@@ -0,0 +1,631 @@
+//! A language server for brainfuck sources, speaking the Language Server
+//! Protocol over stdio.
+//!
+//! Documents are synchronised in full on every change, and reparsed for each
+//! request. The server provides:
+//! - Diagnostics for syntax errors and lints (at their default levels).
+//! - Hover over a loop, showing the net pointer movement of an iteration and
+//!   its effect on each cell.
+//! - Go to definition on a bracket, jumping to the matching bracket.
+//! - Highlighting of the matching pair of brackets.
+//! - Folding ranges for loops spanning multiple lines.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, Write},
+};
+
+use crate::{
+    intermediate::{BrainFuck, Op, Span, SpanTree, Stat},
+    json::{self, Json},
+    lint::{lint, Level},
+    parser::{parse_spanned, SyntaxError},
+};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// The severities of diagnostics.
+const ERROR: i64 = 1;
+const WARNING: i64 = 2;
+
+/// A loop in a document, with its body.
+struct Loop<'a> {
+    span: Span,
+    body: &'a [Stat],
+}
+
+/// The effect of a loop iteration on a cell.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Effect {
+    added: i32,
+    input: bool,
+    output: bool,
+    /// Changed by an inner loop.
+    varies: bool,
+}
+
+struct Server {
+    documents: HashMap<String, String>,
+    before_cells: u32,
+    shutdown: bool,
+}
+
+/// Get the LSP position (the line, and UTF-16 offset in the line, from 0) of a
+/// byte offset in a source.
+fn position(source: &str, offset: usize) -> Json {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Json::object([
+        ("line", before.matches('\n').count().into()),
+        (
+            "character",
+            before[line_start..].encode_utf16().count().into(),
+        ),
+    ])
+}
+
+fn range(source: &str, span: Span) -> Json {
+    Json::object([
+        ("start", position(source, span.start)),
+        ("end", position(source, span.end)),
+    ])
+}
+
+/// Get the byte offset of an LSP position in a source, clamped to the end of
+/// the line.
+fn offset(source: &str, line: usize, character: usize) -> usize {
+    let line_start: usize = source.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+    for (i, c) in source[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    source.len()
+}
+
+/// Collect the loops in some statements, outer loops first.
+fn loops<'a>(stats: &'a [Stat], spans: &'a [SpanTree], result: &mut Vec<Loop<'a>>) {
+    for (stat, SpanTree(span, inner)) in stats.iter().zip(spans) {
+        if let Stat::WhileNonZero(body) = stat {
+            result.push(Loop { span: *span, body });
+            loops(body, inner, result);
+        }
+    }
+}
+
+/// Get the spans of a bracket at (or just before) an offset, and of the
+/// bracket matching it.
+fn brackets(loops: &[Loop], offset: usize) -> Option<(Span, Span)> {
+    [offset, offset.wrapping_sub(1)]
+        .into_iter()
+        .find_map(|offset| {
+            loops.iter().find_map(|Loop { span, .. }| {
+                let open = Span {
+                    start: span.start,
+                    end: span.start + 1,
+                };
+                let close = Span {
+                    start: span.end - 1,
+                    end: span.end,
+                };
+                if offset == open.start {
+                    Some((open, close))
+                } else if offset == close.start {
+                    Some((close, open))
+                } else {
+                    None
+                }
+            })
+        })
+}
+
+/// Add the effects of statements on cells (by offset from the pointer at the
+/// start), returning the final offset if the pointer movement is known.
+fn effects(
+    stats: &[Stat],
+    mut offset: i32,
+    nested: bool,
+    cells: &mut BTreeMap<i32, Effect>,
+) -> Option<i32> {
+    for stat in stats {
+        match stat {
+            Stat::PtrMove(i) => offset += i,
+            Stat::DerefOp(Op::Add, i) if !nested => cells.entry(offset).or_default().added += i,
+            Stat::DerefOp(_, _) => cells.entry(offset).or_default().varies = true,
+            Stat::Input => cells.entry(offset).or_default().input = true,
+            Stat::Output => cells.entry(offset).or_default().output = true,
+            Stat::WhileNonZero(body) => {
+                cells.entry(offset).or_default().varies = true;
+                if effects(body, offset, true, cells)? != offset {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(offset)
+}
+
+/// Describe a loop for a hover, as markdown.
+fn describe(source: &str, Loop { span, body }: &Loop) -> String {
+    let (first, _) = span.location(source);
+    let (last, _) = Span {
+        start: span.end - 1,
+        end: span.end,
+    }
+    .location(source);
+    let mut text = if first == last {
+        format!("**Loop** on line {}\n\n", first)
+    } else {
+        format!("**Loop** from line {} to {}\n\n", first, last)
+    };
+
+    let mut cells = BTreeMap::new();
+    text.push_str(&match effects(body, 0, false, &mut cells) {
+        Some(0) => "Balanced, each iteration leaves the pointer where it started.".to_string(),
+        Some(moved) => format!("Each iteration moves the pointer by {:+}.", moved),
+        None => {
+            "The pointer movement of each iteration depends on an inner loop or insert.".to_string()
+        }
+    });
+
+    if !cells.is_empty() {
+        text.push_str(
+            "\n\nEffects on cells (by offset from the pointer at the start of an iteration):",
+        );
+        for (offset, effect) in cells {
+            let mut parts = Vec::new();
+            match effect.added {
+                0 => (),
+                n if n > 0 => parts.push(format!("adds {}", n)),
+                n => parts.push(format!("subtracts {}", -n)),
+            }
+            if effect.input {
+                parts.push("reads input".to_string())
+            }
+            if effect.output {
+                parts.push("is output".to_string())
+            }
+            if effect.varies {
+                parts.push("changed by an inner loop".to_string())
+            }
+            text.push_str(&format!("\n- `{:+}`: {}", offset, parts.join(", ")));
+        }
+    }
+    text
+}
+
+/// Get the diagnostics for a source.
+fn diagnostics(source: &str, before_cells: u32) -> Vec<Json> {
+    let diagnostic = |span: Span, severity: i64, code: Option<&str>, message: &str| {
+        Json::object([
+            ("range", range(source, span)),
+            ("severity", Json::Number(severity)),
+            ("code", code.into()),
+            ("source", "bfc".into()),
+            ("message", message.into()),
+        ])
+    };
+    match parse_spanned(source) {
+        Ok((ir, spans)) => lint(&ir, &spans, before_cells)
+            .into_iter()
+            .filter_map(|warning| {
+                let severity = match warning.lint.level(&[], &[]) {
+                    Level::Allow => return None,
+                    Level::Warn => WARNING,
+                    Level::Deny => ERROR,
+                };
+                Some(diagnostic(
+                    warning.span,
+                    severity,
+                    Some(warning.lint.name()),
+                    &warning.message,
+                ))
+            })
+            .collect(),
+        Err(SyntaxError { span, message }) => vec![diagnostic(span, ERROR, None, &message)],
+    }
+}
+
+/// Read a message, returning `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let mut content = vec![0; length?];
+    input.read_exact(&mut content).ok()?;
+    // a message that cannot be parsed is treated as null, and ignored
+    Some(json::parse(&String::from_utf8_lossy(&content)).unwrap_or(Json::Null))
+}
+
+fn write_message(output: &mut impl Write, message: Json) {
+    let content = message.to_string();
+    // the client has gone if writing fails, which is noticed on the next read
+    let _ = write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    );
+    let _ = output.flush();
+}
+
+impl Server {
+    /// Get the text of the document given in the parameters of a request.
+    fn document(&self, params: &Json) -> Option<&str> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        self.documents.get(uri).map(String::as_str)
+    }
+
+    /// Get the document and offset of the position given in the parameters of
+    /// a request.
+    fn document_offset(&self, params: &Json) -> Option<(&str, usize)> {
+        let source = self.document(params)?;
+        let position = params.get("position")?;
+        let line = position.get("line")?.as_i64()?;
+        let character = position.get("character")?.as_i64()?;
+        Some((source, offset(source, line as usize, character as usize)))
+    }
+
+    /// Run a function on the loops of the document (and the offset) given in
+    /// the parameters of a request.
+    fn with_loops(
+        &self,
+        params: &Json,
+        f: impl FnOnce(&str, usize, &[Loop]) -> Json,
+    ) -> Option<Json> {
+        let (source, offset) = self.document_offset(params)?;
+        let Ok((BrainFuck(stats), spans)) = parse_spanned(source) else {
+            return Some(Json::Null);
+        };
+        let mut found = Vec::new();
+        loops(&stats, &spans, &mut found);
+        Some(f(source, offset, &found))
+    }
+
+    /// Get the result of a request, or `None` if the parameters are invalid.
+    fn request(&mut self, method: &str, params: &Json) -> Option<Result<Json, i64>> {
+        let uri = || params.get("textDocument")?.get("uri");
+        Some(Ok(match method {
+            "initialize" => Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        ("textDocumentSync", 1.into()),
+                        ("hoverProvider", Json::Bool(true)),
+                        ("definitionProvider", Json::Bool(true)),
+                        ("documentHighlightProvider", Json::Bool(true)),
+                        ("foldingRangeProvider", Json::Bool(true)),
+                    ]),
+                ),
+                ("serverInfo", Json::object([("name", "bfc".into())])),
+            ]),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/hover" => self.with_loops(params, |source, offset, loops| {
+                // the innermost loop containing the position
+                match loops
+                    .iter()
+                    .rev()
+                    .find(|Loop { span, .. }| span.start <= offset && offset < span.end)
+                {
+                    Some(found) => Json::object([
+                        (
+                            "contents",
+                            Json::object([
+                                ("kind", "markdown".into()),
+                                ("value", describe(source, found).as_str().into()),
+                            ]),
+                        ),
+                        ("range", range(source, found.span)),
+                    ]),
+                    None => Json::Null,
+                }
+            })?,
+            "textDocument/definition" => self.with_loops(params, |source, offset, loops| {
+                match brackets(loops, offset) {
+                    Some((_, other)) => Json::object([
+                        ("uri", uri().cloned().unwrap_or(Json::Null)),
+                        ("range", range(source, other)),
+                    ]),
+                    None => Json::Null,
+                }
+            })?,
+            "textDocument/documentHighlight" => {
+                self.with_loops(params, |source, offset, loops| {
+                    match brackets(loops, offset) {
+                        Some((bracket, other)) => Json::Array(
+                            [bracket, other]
+                                .into_iter()
+                                .map(|span| Json::object([("range", range(source, span))]))
+                                .collect(),
+                        ),
+                        None => Json::Null,
+                    }
+                })?
+            }
+            "textDocument/foldingRange" => {
+                let source = self.document(params)?;
+                let Ok((BrainFuck(stats), spans)) = parse_spanned(source) else {
+                    return Some(Ok(Json::Array(vec![])));
+                };
+                let mut found = Vec::new();
+                loops(&stats, &spans, &mut found);
+                Json::Array(
+                    found
+                        .iter()
+                        .filter_map(|Loop { span, .. }| {
+                            let (start, _) = span.location(source);
+                            let (end, _) = Span {
+                                start: span.end - 1,
+                                end: span.end,
+                            }
+                            .location(source);
+                            (start < end).then(|| {
+                                Json::object([
+                                    ("startLine", (start - 1).into()),
+                                    ("endLine", (end - 1).into()),
+                                ])
+                            })
+                        })
+                        .collect(),
+                )
+            }
+            _ => return Some(Err(METHOD_NOT_FOUND)),
+        }))
+    }
+
+    /// Handle a notification, returning any notifications to send back.
+    fn notify(&mut self, method: &str, params: &Json) -> Option<Vec<Json>> {
+        let document = params.get("textDocument")?;
+        let uri = document.get("uri")?.as_str()?.to_string();
+        let text = match method {
+            "textDocument/didOpen" => document.get("text")?.as_str()?.to_string(),
+            "textDocument/didChange" => match params.get("contentChanges")? {
+                Json::Array(changes) => changes.last()?.get("text")?.as_str()?.to_string(),
+                _ => return None,
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return Some(vec![publish(&uri, vec![])]);
+            }
+            _ => return None,
+        };
+        let diagnostics = diagnostics(&text, self.before_cells);
+        self.documents.insert(uri.clone(), text);
+        Some(vec![publish(&uri, diagnostics)])
+    }
+}
+
+fn publish(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        (
+            "params",
+            Json::object([
+                ("uri", uri.into()),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ])
+}
+
+/// Serve a client until it exits, given the number of cells before the initial
+/// pointer position (for lints). The exit code is returned, which is non-zero
+/// if the client exits without a shutdown request.
+pub fn serve(input: &mut impl BufRead, output: &mut impl Write, before_cells: u32) -> i32 {
+    let mut server = Server {
+        documents: HashMap::new(),
+        before_cells,
+        shutdown: false,
+    };
+    while let Some(message) = read_message(input) {
+        let Some(method) = message.get("method").and_then(Json::as_str) else {
+            continue;
+        };
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        if method == "exit" {
+            return if server.shutdown { 0 } else { 1 };
+        }
+
+        match message.get("id") {
+            Some(id) => {
+                let outcome = match server.request(method, &params) {
+                    Some(Ok(result)) => ("result", result),
+                    Some(Err(code)) => ("error", error(code, "Unknown method")),
+                    None => ("error", error(INVALID_PARAMS, "Invalid parameters")),
+                };
+                write_message(
+                    output,
+                    Json::object([("jsonrpc", "2.0".into()), ("id", id.clone()), outcome]),
+                )
+            }
+            None => {
+                for notification in server.notify(method, &params).unwrap_or_default() {
+                    write_message(output, notification)
+                }
+            }
+        }
+    }
+    1
+}
+
+fn error(code: i64, message: &str) -> Json {
+    Json::object([("code", Json::Number(code)), ("message", message.into())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a session with the server, returning the messages sent back.
+    fn session(messages: &[Json]) -> (i32, Vec<Json>) {
+        let mut input = Vec::new();
+        for message in messages {
+            let content = message.to_string();
+            write!(
+                input,
+                "Content-Length: {}\r\n\r\n{}",
+                content.len(),
+                content
+            )
+            .unwrap();
+        }
+        let mut output = Vec::new();
+        let code = serve(&mut input.as_slice(), &mut output, 0);
+        let mut output = output.as_slice();
+        let mut replies = Vec::new();
+        while let Some(reply) = read_message(&mut output) {
+            replies.push(reply)
+        }
+        (code, replies)
+    }
+
+    fn message(id: Option<i64>, method: &str, params: Json) -> Json {
+        let mut fields = vec![("jsonrpc", "2.0".into()), ("method", method.into())];
+        if let Some(id) = id {
+            fields.push(("id", Json::Number(id)));
+        }
+        fields.push(("params", params));
+        Json::object(fields)
+    }
+
+    fn at(line: i64, character: i64) -> Json {
+        Json::object([
+            (
+                "textDocument",
+                Json::object([("uri", "file:///a.bf".into())]),
+            ),
+            (
+                "position",
+                Json::object([
+                    ("line", Json::Number(line)),
+                    ("character", Json::Number(character)),
+                ]),
+            ),
+        ])
+    }
+
+    fn open(text: &str) -> Json {
+        message(
+            None,
+            "textDocument/didOpen",
+            Json::object([(
+                "textDocument",
+                Json::object([("uri", "file:///a.bf".into()), ("text", text.into())]),
+            )]),
+        )
+    }
+
+    #[test]
+    fn converts_positions() {
+        let source = "ab\n\u{1f600}[]\n";
+        assert_eq!(offset(source, 1, 2), 7);
+        assert_eq!(offset(source, 1, 9), 9);
+        assert_eq!(offset(source, 5, 0), source.len());
+        assert_eq!(
+            position(source, 7).to_string(),
+            r#"{"line":1,"character":2}"#
+        );
+    }
+
+    #[test]
+    fn describes_loops() {
+        let source = ",[->+++<\n[-]>>.<<]";
+        let (BrainFuck(stats), spans) = parse_spanned(source).unwrap();
+        let mut found = Vec::new();
+        loops(&stats, &spans, &mut found);
+        assert_eq!(
+            describe(source, &found[0]),
+            "**Loop** from line 1 to 2\n\n\
+             Balanced, each iteration leaves the pointer where it started.\n\n\
+             Effects on cells (by offset from the pointer at the start of an iteration):\n\
+             - `+0`: subtracts 1, changed by an inner loop\n\
+             - `+1`: adds 3\n\
+             - `+2`: is output"
+        );
+        let (BrainFuck(stats), spans) = parse_spanned("[>,[>]]").unwrap();
+        let mut found = Vec::new();
+        loops(&stats, &spans, &mut found);
+        assert!(describe("[>,[>]]", &found[0]).contains("depends on an inner loop"));
+    }
+
+    #[test]
+    fn serves_a_session() {
+        let (code, replies) = session(&[
+            message(Some(1), "initialize", Json::object([])),
+            open("+[\n>[-]<]"),
+            message(Some(2), "textDocument/definition", at(0, 1)),
+            message(Some(3), "textDocument/foldingRange", at(0, 0)),
+            message(Some(4), "textDocument/hover", at(1, 2)),
+            open("+[]]"),
+            message(Some(5), "unknown", Json::Null),
+            message(Some(6), "shutdown", Json::Null),
+            message(None, "exit", Json::Null),
+        ]);
+        assert_eq!(code, 0);
+        assert_eq!(replies.len(), 8);
+        assert!(replies[0]
+            .get("result")
+            .unwrap()
+            .get("capabilities")
+            .is_some());
+        // the first loop never changes the (non-zero) cell it is entered on
+        assert!(replies[1].to_string().contains(r#""code":"infinite-loop""#));
+        assert_eq!(
+            replies[2]
+                .get("result")
+                .unwrap()
+                .get("range")
+                .unwrap()
+                .to_string(),
+            r#"{"start":{"line":1,"character":5},"end":{"line":1,"character":6}}"#
+        );
+        assert_eq!(
+            replies[3].get("result").unwrap().to_string(),
+            r#"[{"startLine":0,"endLine":1}]"#
+        );
+        assert!(replies[4].to_string().contains("**Loop** on line 2"));
+        assert!(replies[5].to_string().contains(
+            r#""range":{"start":{"line":0,"character":3},"end":{"line":0,"character":4}}"#
+        ));
+        assert_eq!(
+            replies[6].get("error").unwrap().get("code"),
+            Some(&Json::Number(METHOD_NOT_FOUND))
+        );
+        assert_eq!(replies[7].get("result"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn reports_errors_on_multibyte_characters() {
+        let diagnostics = diagnostics("+[->+<] é\u{1f600}", 0);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].get("range").unwrap().to_string(),
+            r#"{"start":{"line":0,"character":8},"end":{"line":0,"character":9}}"#
+        );
+        assert_eq!(
+            diagnostics[0].get("message").and_then(Json::as_str),
+            Some("Invalid character 'é'")
+        );
+    }
+}
@@ -22,34 +22,102 @@
 //!
 //! USAGE:
 //!     bfc [OPTIONS] <FILE>
+//!     bfc <SUBCOMMAND>
 //!
 //! ARGS:
 //!     <FILE>    
 //!
 //! OPTIONS:
+//!     -A, --allow <LINT>
+//!             Do not report a lint [possible values: infinite-loop, unreachable-code,
+//!             pointer-underflow, unbalanced-loop]
+//!
 //!     -a, --after-cells <AFTER_CELLS>
 //!             The number of byte cells to the right of the initial pointer position [default: 30000]
 //!
 //!     -b, --before-cells <BEFORE_CELLS>
 //!             The number of byte cells to the left of the initial pointer position [default: 0]
 //!
+//!     -D, --deny <LINT>
+//!             Report a lint as an error, stopping compilation [possible values: infinite-loop,
+//!             unreachable-code, pointer-underflow, unbalanced-loop]
+//!
+//!         --emit <KIND>
+//!             Emit the intermediate representation (after optimisation for the target) as JSON, rather
+//!             than compiling [possible values: json-ir]
+//!
+//!         --emit-ir-after <PASS>
+//!             View the intermediate representation after an optimiser pass [possible values: constant,
+//!             rules, scan, loops, values]
+//!
+//!         --freestanding
+//!             Compile without libc or syscalls, writing a linker script alongside the output
+//!
 //!     -h, --help
 //!             Print help information
 //!
+//!         --input-fn <NAME>
+//!             The external function called to get input for the current cell [default: bf_getchar]
+//!
+//!         --input-template <CODE>
+//!             Code to insert for input, in place of calling a function
+//!
+//!         --load-address <ADDRESS>
+//!             The address the program is linked at [default: 0x80000000]
+//!
+//!         --max-output <BYTES>
+//!             Stop the interpreter after outputting a number of bytes
+//!
+//!         --max-steps <STEPS>
+//!             Stop after running a number of statements and loop iterations
+//!
+//!         --message-format <FORMAT>
+//!             Report errors, lints and remarks for people, or as JSON [default: human] [possible
+//!             values: human, json]
+//!
 //!     -o, --outputpath <FILE>
 //!             The name of the output file
 //!
+//!         --output-fn <NAME>
+//!             The external function called to output the current cell [default: bf_putchar]
+//!
+//!         --output-template <CODE>
+//!             Code to insert for output, in place of calling a function
+//!
 //!     -p, --print-result
 //!             print the compilation result rather than writing to a file
 //!
+//!         --print-remarks
+//!             Report the changes made by each optimiser pass
+//!
+//!         --rules <FILE>
+//!             Replace patterns with inserts for the target, using the rules in a file
+//!
 //!     -t, --target <TARGET>
-//!             Set the target [default: interpreter] [possible values: interpreter, c99, arm]
+//!             Set the target [default: interpreter] [possible values: interpreter, c99, arm, bf, riscv64,
+//!             aarch64, x86-kernel, rust, javascript, python, jvm]
+//!
+//!         --tape-address <ADDRESS>
+//!             Place the tape at a fixed address
+//!
+//!         --timeout <SECONDS>
+//!             Stop the interpreter after a number of seconds
+//!
+//!         --unbuffered
+//!             Write output immediately, rather than buffering until a newline or input
 //!
 //!     -u, --unoptimised
 //!             View the unoptimised intermediate representation
 //!
 //!     -V, --version
 //!             Print version information
+//!
+//! SUBCOMMANDS:
+//!     build   Build a standalone x86-64 Linux executable
+//!     fmt     Reformat a brainfuck source file
+//!     help    Print this message or the help of the given subcommand(s)
+//!     lsp     Run a language server for brainfuck sources over stdio
+//!     repl    Run lines of brainfuck interactively on a persistent tape
 //! ```
 //!
 //! ## Exit Codes:
@@ -60,38 +128,84 @@
 //! | 2         | File Write Failure     |
 //! | 3         | File Create Failure    |
 //! | 100       | Syntax Error           |
+//! | 101       | Denied Lint            |
+//! | 102       | Unsupported Insert     |
+//! | 103       | Unsupported Target     |
+//! | 104       | Program Too Large      |
+//! | 105       | Runtime Error          |
+//! | 106       | Step Limit Reached     |
+//! | 107       | Time Limit Reached     |
+//! | 108       | Output Limit Reached   |
 
 #![feature(fn_traits)]
-#![feature(fmt_internals)]
+#![feature(formatting_options)]
 #![allow(dead_code)]
 
+mod diagnostic;
+mod format;
 mod intermediate;
+mod interpreter;
+mod json;
+mod lint;
+mod lsp;
+mod optimiser;
 mod parser;
+mod repl;
+mod syntax;
 mod target;
 
 use std::{
-    fs::{read_to_string, File},
+    fs::{read_to_string, File, OpenOptions},
     io::Write,
-    path::PathBuf,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
     process::exit,
+    time::{Duration, Instant},
 };
 
-use clap::{ArgEnum, Parser};
-use parser::parse;
-use target::{compile, Backend};
+use clap::{ArgEnum, Parser, Subcommand};
+use diagnostic::{Diagnostic, Kind, MessageFormat};
+use format::{format, Style};
+use intermediate::{BrainFuck, Spans};
+use interpreter::{Machine, Unbuffered};
+use lint::{lint, Level, Lint};
+use optimiser::{optimise, parse_rules, Pass, Rule};
+use parser::{parse_spanned, parse_syntax, SyntaxError};
+use target::{
+    build, build_class, compile, link_script, Backend, ClassError, Freestanding, Hook, Settings,
+    Source,
+};
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
 enum Target {
     Interpreter,
     C99,
     Arm,
+    Bf,
+    Riscv64,
+    Aarch64,
+    X86Kernel,
+    Rust,
+    Javascript,
+    Python,
+    Jvm,
+}
+
+/// The output to emit in place of compiling for the target.
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum)]
+enum Emit {
+    JsonIr,
 }
 
 #[derive(Parser)]
 #[clap(author = "Oliver Killane", about = "BrainFuck compiler" , long_about = Some("A brainfuck compiler targeting multiple architectures"), version = "0.0.1")]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[clap(parse(from_os_str), value_name = "FILE")]
-    input_path: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(parse(from_os_str), value_name = "FILE", required = true)]
+    input_path: Option<PathBuf>,
 
     #[clap(
         short,
@@ -112,6 +226,161 @@ struct Args {
     )]
     target: Target,
 
+    #[clap(short, long, help = "View the unoptimised intermediate representation")]
+    unoptimised: bool,
+
+    #[clap(
+        short,
+        long,
+        help = "print the compilation result rather than writing to a file"
+    )]
+    print_result: bool,
+
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "FILE",
+        help = "Replace patterns with inserts for the target, using the rules in a file"
+    )]
+    rules: Option<PathBuf>,
+
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "KIND",
+        help = "Emit the intermediate representation (after optimisation for the target) as JSON, rather than compiling"
+    )]
+    emit: Option<Emit>,
+
+    #[clap(flatten)]
+    options: Options,
+
+    #[clap(flatten)]
+    freestanding: FreestandingOptions,
+
+    #[clap(flatten)]
+    limits: Limits,
+}
+
+/// Limits on running untrusted programs.
+#[derive(Parser)]
+struct Limits {
+    #[clap(
+        long,
+        value_name = "STEPS",
+        help = "Stop after running a number of statements and loop iterations"
+    )]
+    max_steps: Option<u64>,
+
+    #[clap(
+        long,
+        parse(try_from_str = parse_seconds),
+        value_name = "SECONDS",
+        help = "Stop the interpreter after a number of seconds"
+    )]
+    timeout: Option<Duration>,
+
+    #[clap(
+        long,
+        value_name = "BYTES",
+        help = "Stop the interpreter after outputting a number of bytes"
+    )]
+    max_output: Option<u64>,
+}
+
+/// Parse a (possibly fractional) number of seconds.
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    let seconds: f64 = seconds.parse().map_err(|err| format!("{}", err))?;
+    Duration::try_from_secs_f64(seconds).map_err(|err| format!("{}", err))
+}
+
+/// Options for compiling programs to run without an operating system.
+#[derive(Parser)]
+struct FreestandingOptions {
+    #[clap(
+        long,
+        help = "Compile without libc or syscalls, writing a linker script alongside the output"
+    )]
+    freestanding: bool,
+
+    #[clap(
+        long,
+        default_value = "bf_putchar",
+        value_name = "NAME",
+        help = "The external function called to output the current cell"
+    )]
+    output_fn: String,
+
+    #[clap(
+        long,
+        default_value = "bf_getchar",
+        value_name = "NAME",
+        help = "The external function called to get input for the current cell"
+    )]
+    input_fn: String,
+
+    #[clap(
+        long,
+        value_name = "CODE",
+        conflicts_with = "output-fn",
+        help = "Code to insert for output, in place of calling a function"
+    )]
+    output_template: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "CODE",
+        conflicts_with = "input-fn",
+        help = "Code to insert for input, in place of calling a function"
+    )]
+    input_template: Option<String>,
+
+    #[clap(
+        long,
+        parse(try_from_str = parse_address),
+        value_name = "ADDRESS",
+        help = "Place the tape at a fixed address"
+    )]
+    tape_address: Option<u64>,
+
+    #[clap(
+        long,
+        parse(try_from_str = parse_address),
+        default_value = "0x80000000",
+        value_name = "ADDRESS",
+        help = "The address the program is linked at"
+    )]
+    load_address: u64,
+}
+
+/// Parse an address in hexadecimal (with 0x) or decimal.
+fn parse_address(address: &str) -> Result<u64, std::num::ParseIntError> {
+    match address.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => address.parse(),
+    }
+}
+
+impl FreestandingOptions {
+    fn settings(self) -> Option<Freestanding> {
+        self.freestanding.then_some(Freestanding {
+            output: match self.output_template {
+                Some(code) => Hook::Template(code),
+                None => Hook::Extern(self.output_fn),
+            },
+            input: match self.input_template {
+                Some(code) => Hook::Template(code),
+                None => Hook::Extern(self.input_fn),
+            },
+            tape_address: self.tape_address,
+            load_address: self.load_address,
+        })
+    }
+}
+
+/// Options shared by all commands compiling a program.
+#[derive(Parser)]
+struct Options {
     #[clap(
         short,
         long,
@@ -128,15 +397,149 @@ struct Args {
     )]
     after_cells: u32,
 
-    #[clap(short, long, help = "View the unoptimised intermediate representation")]
-    unoptimised: bool,
+    #[clap(
+        short = 'A',
+        long,
+        arg_enum,
+        multiple_occurrences = true,
+        value_name = "LINT",
+        help = "Do not report a lint"
+    )]
+    allow: Vec<Lint>,
 
     #[clap(
-        short,
+        short = 'D',
         long,
-        help = "print the compilation result rather than writing to a file"
+        arg_enum,
+        multiple_occurrences = true,
+        value_name = "LINT",
+        help = "Report a lint as an error, stopping compilation"
     )]
-    print_result: bool,
+    deny: Vec<Lint>,
+
+    #[clap(
+        long,
+        help = "Write output immediately, rather than buffering until a newline or input"
+    )]
+    unbuffered: bool,
+
+    #[clap(
+        long,
+        arg_enum,
+        multiple_occurrences = true,
+        value_name = "PASS",
+        help = "View the intermediate representation after an optimiser pass"
+    )]
+    emit_ir_after: Vec<Pass>,
+
+    #[clap(long, help = "Report the changes made by each optimiser pass")]
+    print_remarks: bool,
+
+    #[clap(
+        long,
+        arg_enum,
+        default_value_t = MessageFormat::Human,
+        value_name = "FORMAT",
+        help = "Report errors, lints and remarks for people, or as JSON"
+    )]
+    message_format: MessageFormat,
+}
+
+impl Options {
+    /// The settings for compiling a hosted program.
+    fn settings(&self) -> Settings<'static> {
+        Settings {
+            pre: self.before_cells,
+            post: self.after_cells,
+            freestanding: None,
+            buffered: !self.unbuffered,
+            source: None,
+            max_steps: None,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[clap(about = "Reformat a brainfuck source file")]
+    Fmt {
+        #[clap(parse(from_os_str), value_name = "FILE")]
+        input_path: PathBuf,
+
+        #[clap(
+            short,
+            long,
+            default_value_t = 80,
+            help = "The maximum width of a line"
+        )]
+        width: usize,
+
+        #[clap(
+            short,
+            long,
+            default_value_t = 4,
+            help = "The number of spaces to indent loop bodies by"
+        )]
+        indent: usize,
+
+        #[clap(short, long, help = "Do not separate runs of commands with spaces")]
+        no_group: bool,
+
+        #[clap(
+            short,
+            long,
+            help = "print the formatted source rather than overwriting the file"
+        )]
+        print_result: bool,
+    },
+
+    #[clap(about = "Run a language server for brainfuck sources over stdio")]
+    Lsp {
+        #[clap(
+            short,
+            long,
+            default_value_t = 0,
+            help = "The number of byte cells to the left of the initial pointer position"
+        )]
+        before_cells: u32,
+    },
+
+    #[clap(about = "Run lines of brainfuck interactively on a persistent tape")]
+    Repl {
+        #[clap(
+            short,
+            long,
+            default_value_t = 0,
+            help = "The number of byte cells to the left of the initial pointer position"
+        )]
+        before_cells: u32,
+
+        #[clap(
+            short,
+            long,
+            default_value_t = 30_000,
+            help = "The number of byte cells to the right of the initial pointer position"
+        )]
+        after_cells: u32,
+    },
+
+    #[clap(about = "Build a standalone x86-64 Linux executable")]
+    Build {
+        #[clap(parse(from_os_str), value_name = "FILE")]
+        input_path: PathBuf,
+
+        #[clap(
+            short,
+            long,
+            parse(from_os_str),
+            value_name = "FILE",
+            help = "The name of the output file"
+        )]
+        output_path: Option<PathBuf>,
+
+        #[clap(flatten)]
+        options: Options,
+    },
 }
 
 const EXIT_SUCCESS: i32 = 0;
@@ -144,76 +547,487 @@ const FILE_READ_FAILURE: i32 = 1;
 const FILE_WRITE_FAILURE: i32 = 2;
 const FILE_CREATE_FAILURE: i32 = 3;
 const SYNTAX_ERROR: i32 = 100;
+const DENIED_LINT: i32 = 101;
+const UNSUPPORTED_INSERT: i32 = 102;
+const UNSUPPORTED_TARGET: i32 = 103;
+const PROGRAM_TOO_LARGE: i32 = 104;
+const RUNTIME_ERROR: i32 = 105;
+const STEP_LIMIT_REACHED: i32 = 106;
+const TIME_LIMIT_REACHED: i32 = 107;
+const OUTPUT_LIMIT_REACHED: i32 = 108;
+
+/// Report an error in the message format, and exit with a code.
+fn fail(message: impl Into<String>, format: MessageFormat, code: i32) -> ! {
+    Diagnostic::error(message).report("", format);
+    exit(code)
+}
+
+/// Reformat a source file in place (or print it).
+fn format_file(input_path: PathBuf, style: Style, print_result: bool) -> ! {
+    let source = if let Ok(source) = read_to_string(&input_path) {
+        source
+    } else {
+        println!("Unable to open file");
+        exit(FILE_READ_FAILURE)
+    };
+
+    let formatted = match parse_syntax(&source) {
+        Ok(syntax) => format(&syntax, &style),
+        Err(_err) => {
+            println!("An error occured");
+            exit(SYNTAX_ERROR)
+        }
+    };
+
+    if print_result {
+        print!("{}", formatted)
+    } else {
+        let mut output_file = if let Ok(file) = File::create(input_path) {
+            file
+        } else {
+            exit(FILE_CREATE_FAILURE)
+        };
+
+        if write!(output_file, "{}", formatted).is_err() {
+            exit(FILE_WRITE_FAILURE);
+        }
+    }
+
+    exit(EXIT_SUCCESS)
+}
+
+/// Read, parse and lint a program, exiting on any errors. The source and
+/// spans of the program are returned with it.
+fn load(input_path: &Path, options: &Options) -> (String, BrainFuck, Spans) {
+    let format = options.message_format;
+    let source = if let Ok(source) = read_to_string(input_path) {
+        source
+    } else {
+        fail("Unable to open file", format, FILE_READ_FAILURE)
+    };
+
+    let (ir, spans) = match parse_spanned(&source) {
+        Ok(parsed) => parsed,
+        Err(SyntaxError { span, message }) => {
+            Diagnostic {
+                span: Some(span),
+                ..Diagnostic::error(message)
+            }
+            .report(&source, format);
+            exit(SYNTAX_ERROR)
+        }
+    };
+
+    let mut denied = false;
+    for warning in lint(&ir, &spans, options.before_cells) {
+        let kind = match warning.lint.level(&options.allow, &options.deny) {
+            Level::Allow => continue,
+            Level::Warn => Kind::Warning,
+            Level::Deny => {
+                denied = true;
+                Kind::Error
+            }
+        };
+        Diagnostic {
+            kind,
+            code: Some(warning.lint.name()),
+            span: Some(warning.span),
+            message: warning.message,
+        }
+        .report(&source, format);
+    }
+    if denied {
+        exit(DENIED_LINT)
+    }
+
+    (source, ir, spans)
+}
+
+/// Read the rules for a target from a rule file, exiting on any errors.
+fn load_rules(rules_path: &Path, target: Target, format: MessageFormat) -> Vec<Rule> {
+    let text = if let Ok(text) = read_to_string(rules_path) {
+        text
+    } else {
+        fail("Unable to open rule file", format, FILE_READ_FAILURE)
+    };
+
+    let rules = match parse_rules(&text) {
+        Ok(rules) => rules,
+        Err(err) => fail(format!("Invalid rule file, {}", err), format, SYNTAX_ERROR),
+    };
+
+    let mut result = Vec::new();
+    for rule in rules {
+        match Target::from_str(&rule.target, false) {
+            Ok(rule_target) if rule_target == target => result.push(rule),
+            Ok(_) => (),
+            Err(_) => fail(
+                format!("Unknown target {} for rule {}", rule.target, rule.name),
+                format,
+                SYNTAX_ERROR,
+            ),
+        }
+    }
+    result
+}
+
+/// Optimise a program (using the rules for its target), printing the remarks
+/// and intermediate representations requested in the options. Nothing is run
+/// at compile time under a step limit, as those statements would not count
+/// towards it. The optimised program is returned with its spans.
+fn optimise_program(
+    ir: BrainFuck,
+    spans: Spans,
+    source: &str,
+    rules: &[Rule],
+    options: &Options,
+    max_steps: Option<u64>,
+) -> (BrainFuck, Spans) {
+    optimise(
+        ir,
+        spans,
+        max_steps
+            .is_none()
+            .then_some((options.before_cells, options.after_cells)),
+        rules,
+        &mut |pass, ir, remarks| {
+            if options.print_remarks {
+                for remark in remarks {
+                    Diagnostic {
+                        kind: Kind::Remark,
+                        code: Some(pass.name()),
+                        span: remark.span,
+                        message: remark.message,
+                    }
+                    .report(source, options.message_format)
+                }
+            }
+            if options.emit_ir_after.contains(&pass) {
+                println!("Intermediate representation after {}:\n{}", pass.name(), ir)
+            }
+        },
+    )
+}
+
+/// Build an executable from a source file.
+fn build_file(input_path: PathBuf, output_path: Option<PathBuf>, options: Options) -> ! {
+    let (source, ir, spans) = load(&input_path, &options);
+    let (ir, _) = optimise_program(ir, spans, &source, &[], &options, None);
+
+    let executable = match build(&ir, &options.settings()) {
+        Ok(executable) => executable,
+        Err(asm) => fail(
+            format!("Inserts cannot be built into an executable: ::{}::", asm),
+            options.message_format,
+            UNSUPPORTED_INSERT,
+        ),
+    };
+
+    let mut output_file = if let Ok(file) = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(output_path.unwrap_or_else(|| input_path.with_extension("")))
+    {
+        file
+    } else {
+        exit(FILE_CREATE_FAILURE)
+    };
+
+    if output_file.write_all(&executable).is_err() {
+        exit(FILE_WRITE_FAILURE);
+    }
+
+    exit(EXIT_SUCCESS)
+}
+
+/// Run a program with the interpreter, using stdin and stdout.
+fn interpret(BrainFuck(stats): &BrainFuck, options: &Options, limits: &Limits) -> ! {
+    let mut machine = Machine::new(options.before_cells, options.after_cells);
+    machine.steps = limits.max_steps;
+    machine.deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+    machine.output_limit = limits.max_output;
+    let stdout = std::io::stdout();
+    // stdout is line buffered
+    let mut output: Box<dyn Write> = if options.unbuffered {
+        Box::new(Unbuffered(stdout.lock()))
+    } else {
+        Box::new(stdout.lock())
+    };
+
+    let result = machine.run(stats, &mut std::io::stdin().lock(), &mut output);
+    if output.flush().is_err() {
+        exit(FILE_WRITE_FAILURE)
+    }
+
+    if let Err(err) = result {
+        let code = match err {
+            interpreter::Error::StepLimit => STEP_LIMIT_REACHED,
+            interpreter::Error::Timeout => TIME_LIMIT_REACHED,
+            interpreter::Error::OutputLimit => OUTPUT_LIMIT_REACHED,
+            _ => RUNTIME_ERROR,
+        };
+        fail(err.to_string(), options.message_format, code)
+    }
+
+    exit(EXIT_SUCCESS)
+}
+
+/// Write a JVM class file for a program, named after the output (or input)
+/// file.
+fn write_class(
+    ir: &BrainFuck,
+    input_path: &Path,
+    output_path: Option<PathBuf>,
+    print_result: bool,
+    options: &Options,
+) -> ! {
+    let output_path = output_path.unwrap_or_else(|| {
+        input_path
+            .with_file_name(class_name(input_path))
+            .with_extension("class")
+    });
+
+    let class = match build_class(ir, &class_name(&output_path), &options.settings()) {
+        Ok(class) => class,
+        Err(ClassError::Insert(insert)) => fail(
+            format!("Inserts cannot be built into a class file: ::{}::", insert),
+            options.message_format,
+            UNSUPPORTED_INSERT,
+        ),
+        Err(ClassError::TooLarge) => fail(
+            "The program is too large for a class file",
+            options.message_format,
+            PROGRAM_TOO_LARGE,
+        ),
+    };
+
+    let written = if print_result {
+        std::io::stdout().write_all(&class)
+    } else if let Ok(mut file) = File::create(output_path) {
+        file.write_all(&class)
+    } else {
+        exit(FILE_CREATE_FAILURE)
+    };
+
+    if written.is_err() {
+        exit(FILE_WRITE_FAILURE);
+    }
+
+    exit(EXIT_SUCCESS)
+}
+
+/// Write the intermediate representation of a program as JSON to a file (or
+/// print it).
+fn emit_json_ir(
+    ir: &BrainFuck,
+    spans: &Spans,
+    source: &str,
+    output_path: Option<PathBuf>,
+    format: MessageFormat,
+) -> ! {
+    let json = ir.to_json(spans, source);
+    match output_path {
+        Some(output_path) => {
+            let mut output_file = if let Ok(file) = File::create(output_path) {
+                file
+            } else {
+                fail("Unable to create file", format, FILE_CREATE_FAILURE)
+            };
+            if writeln!(output_file, "{}", json).is_err() {
+                fail("Unable to write file", format, FILE_WRITE_FAILURE)
+            }
+        }
+        None => println!("{}", json),
+    }
+    exit(EXIT_SUCCESS)
+}
+
+/// Get a valid Java class name from the stem of a file.
+fn class_name(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let name: String = stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| !c.is_ascii_alphabetic() && c != '_') || name.is_empty() {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
 
 fn main() {
     let Args {
-        mut input_path,
+        command,
+        input_path,
         output_path,
-        before_cells,
-        after_cells,
         target,
         unoptimised,
         print_result,
+        rules,
+        emit,
+        options,
+        freestanding,
+        limits,
     } = Args::parse();
 
-    match read_to_string(input_path.clone()) {
-        Ok(source) => {
-            match parse(&source) {
-                Ok(ir) => {
-                    if unoptimised {
-                        println!("Unoptimised intermediate representation:\n{}", ir)
-                    }
+    match command {
+        Some(Command::Fmt {
+            input_path,
+            width,
+            indent,
+            no_group,
+            print_result,
+        }) => format_file(
+            input_path,
+            Style {
+                width,
+                indent,
+                group_runs: !no_group,
+            },
+            print_result,
+        ),
+        Some(Command::Build {
+            input_path,
+            output_path,
+            options,
+        }) => build_file(input_path, output_path, options),
+        Some(Command::Lsp { before_cells }) => exit(lsp::serve(
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+            before_cells,
+        )),
+        Some(Command::Repl {
+            before_cells,
+            after_cells,
+        }) => {
+            repl::repl(
+                &mut std::io::stdin().lock(),
+                &mut std::io::stdout().lock(),
+                before_cells,
+                after_cells,
+            );
+            exit(EXIT_SUCCESS)
+        }
+        None => (),
+    }
 
-                    // todo optimisation
-
-                    if target == Target::Interpreter {
-                        println!("Interpreter runs here")
-                    } else {
-                        let (result, ext) = compile(
-                            match target {
-                                Target::Interpreter => {
-                                    panic!("Cannot set interpreter as compile backend")
-                                }
-                                Target::C99 => Backend::C99,
-                                Target::Arm => unimplemented!(),
-                            },
-                            &ir,
-                            before_cells,
-                            after_cells,
-                        );
-
-                        if print_result {
-                            println!("Compiler Result:\n{}", result)
-                        } else {
-                            let mut output_file = if let Ok(file) = match output_path {
-                                Some(path) => File::create(path),
-                                None => {
-                                    input_path.set_extension(ext);
-                                    File::create(input_path)
-                                }
-                            } {
-                                file
-                            } else {
-                                exit(FILE_CREATE_FAILURE)
-                            };
-
-                            if write!(output_file, "{}", result).is_err() {
-                                exit(FILE_WRITE_FAILURE);
-                            }
-                        }
-                    }
+    let mut input_path = input_path.expect("input file is required without a subcommand");
+    let (source, ir, spans) = load(&input_path, &options);
 
-                    exit(EXIT_SUCCESS)
-                }
-                Err(_err) => {
-                    println!("An error occured");
-                    exit(SYNTAX_ERROR)
-                }
+    if unoptimised {
+        println!("Unoptimised intermediate representation:\n{}", ir)
+    }
+
+    let rules = rules
+        .map(|rules_path| load_rules(&rules_path, target, options.message_format))
+        .unwrap_or_default();
+
+    let (ir, spans) = optimise_program(ir, spans, &source, &rules, &options, limits.max_steps);
+
+    if emit == Some(Emit::JsonIr) {
+        emit_json_ir(&ir, &spans, &source, output_path, options.message_format)
+    }
+
+    if target == Target::Interpreter {
+        interpret(&ir, &options, &limits)
+    }
+
+    if limits.timeout.is_some() || limits.max_output.is_some() {
+        fail(
+            "Only the interpreter can limit time and output",
+            options.message_format,
+            UNSUPPORTED_TARGET,
+        )
+    }
+
+    if target == Target::Jvm {
+        if limits.max_steps.is_some() {
+            fail(
+                "The target does not support step limits",
+                options.message_format,
+                UNSUPPORTED_TARGET,
+            )
+        }
+        write_class(&ir, &input_path, output_path, print_result, &options)
+    } else {
+        let backend = match target {
+            Target::Interpreter => {
+                panic!("Cannot set interpreter as compile backend")
             }
+            Target::C99 => Backend::C99,
+            Target::Arm => unimplemented!(),
+            Target::Bf => Backend::Bf,
+            Target::Riscv64 => Backend::RiscV64,
+            Target::Aarch64 => Backend::AArch64,
+            Target::X86Kernel => Backend::X86Kernel,
+            Target::Rust => Backend::Rust,
+            Target::Javascript => Backend::JavaScript,
+            Target::Python => Backend::Python,
+            Target::Jvm => panic!("Cannot set jvm as compile backend"),
+        };
+        let settings = Settings {
+            freestanding: freestanding.settings(),
+            source: (target == Target::Python).then_some(Source {
+                text: &source,
+                spans: &spans,
+            }),
+            max_steps: limits.max_steps,
+            ..options.settings()
+        };
+        if settings.freestanding.is_some() && !backend.supports_freestanding() {
+            fail(
+                "The target does not support freestanding compilation",
+                options.message_format,
+                UNSUPPORTED_TARGET,
+            )
+        }
+        if settings.max_steps.is_some() && !backend.supports_step_limit() {
+            fail(
+                "The target does not support step limits",
+                options.message_format,
+                UNSUPPORTED_TARGET,
+            )
         }
-        Err(_) => {
-            println!("Unable to open file");
-            exit(FILE_READ_FAILURE)
+
+        let (result, ext) = compile(backend, &ir, &settings);
+        let script = settings.freestanding.as_ref().map(link_script);
+
+        if print_result {
+            println!("Compiler Result:\n{}", result);
+            if let Some(script) = script {
+                println!("Linker Script:\n{}", script)
+            }
+        } else {
+            let output_path = output_path.unwrap_or_else(|| {
+                input_path.set_extension(ext);
+                input_path
+            });
+
+            for (path, contents) in [
+                Some((output_path.clone(), result)),
+                script.map(|script| (output_path.with_extension("ld"), script)),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                let mut output_file = if let Ok(file) = File::create(path) {
+                    file
+                } else {
+                    exit(FILE_CREATE_FAILURE)
+                };
+
+                if write!(output_file, "{}", contents).is_err() {
+                    exit(FILE_WRITE_FAILURE);
+                }
+            }
         }
     }
+
+    exit(EXIT_SUCCESS)
 }
@@ -0,0 +1,199 @@
+//! Partial evaluation of the start of a program.
+//!
+//! Until the first input the tape is known (initially zeroed), so statements
+//! before it can be run at compile time, and replaced with the output they
+//! produce and a single tape initialisation. For example `++>+++.,` becomes:
+//! ```text
+//! "\x03"=(0){2,3}>,
+//! ```
+
+use std::io::empty;
+
+use super::{count, Remark};
+use crate::{
+    intermediate::{BrainFuck, Span, SpanTree, Spans, Stat},
+    interpreter::Machine,
+};
+
+/// The maximum number of statements run at compile time.
+const STEP_LIMIT: u64 = 1_000_000;
+
+/// Check if a statement depends on anything other than the tape.
+fn is_unknown(stat: &Stat) -> bool {
+    match stat {
+        Stat::Input | Stat::Asm(_) => true,
+        Stat::WhileNonZero(stats) => stats.iter().any(is_unknown),
+        _ => false,
+    }
+}
+
+/// Run the top level statements before the first input (or insert), and
+/// replace them with a tape initialisation and constant output (each spanning
+/// the statements run).
+pub fn fold_prefix(
+    BrainFuck(mut stats): BrainFuck,
+    mut spans: Spans,
+    pre: u32,
+    post: u32,
+    remarks: &mut Vec<Remark>,
+) -> (BrainFuck, Spans) {
+    let mut machine = Machine::new(pre, post);
+    machine.steps = Some(STEP_LIMIT);
+    let mut output = Vec::new();
+    let mut folded = 0;
+
+    for stat in &stats {
+        if is_unknown(stat) {
+            break;
+        }
+        // only loops can fail after changing the machine
+        let before = match stat {
+            Stat::WhileNonZero(_) => Some((machine.clone(), output.len())),
+            _ => None,
+        };
+        if machine
+            .run(&vec![stat.clone()], &mut empty(), &mut output)
+            .is_err()
+        {
+            if let Some((snapshot, len)) = before {
+                machine = snapshot;
+                output.truncate(len);
+            }
+            break;
+        }
+        folded += 1;
+    }
+
+    if folded == 0 {
+        return (BrainFuck(stats), spans);
+    }
+
+    let rest = stats.split_off(folded);
+    let rest_spans = spans.split_off(folded);
+    let span = Span {
+        start: spans[0].0.start,
+        end: spans[folded - 1].0.end,
+    };
+    remarks.push(Remark::at(
+        span,
+        format!("ran {} at compile time", count(folded, "statement")),
+    ));
+    let (tape, start) = machine.tape();
+    let mut result = Vec::new();
+
+    // the output doesn't depend on the tape, so is done first (while the tape
+    // is still zeroed)
+    if !output.is_empty() {
+        result.push(Stat::OutputConst(output));
+    }
+
+    // when the whole program is folded, only the output matters
+    let nonzero = (
+        tape.iter().position(|cell| *cell != 0),
+        tape.iter().rposition(|cell| *cell != 0),
+    );
+    if let (false, (Some(first), Some(last))) = (rest.is_empty(), nonzero) {
+        result.push(Stat::SetCells(
+            first as i32 - start as i32,
+            tape[first..=last].to_vec(),
+        ))
+    }
+
+    if !rest.is_empty() && machine.ptr() != 0 {
+        result.push(Stat::PtrMove(machine.ptr()))
+    }
+
+    let mut result_spans = vec![SpanTree(span, vec![]); result.len()];
+    result.extend(rest);
+    result_spans.extend(rest_spans);
+    (BrainFuck(result), result_spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        intermediate::Op,
+        parser::{parse, parse_spanned},
+    };
+
+    fn fold(source: &str) -> BrainFuck {
+        let (bf, spans) = parse_spanned(source).unwrap();
+        fold_prefix(bf, spans, 0, 100, &mut Vec::new()).0
+    }
+
+    #[test]
+    fn folds_whole_program() {
+        assert_eq!(
+            fold("++>+++.<."),
+            BrainFuck(vec![Stat::OutputConst(vec![3, 2])])
+        );
+        assert_eq!(fold("+[-]>>"), BrainFuck(vec![]));
+    }
+
+    #[test]
+    fn folds_up_to_input() {
+        assert_eq!(
+            fold("++>+++.,[-]"),
+            BrainFuck(vec![
+                Stat::OutputConst(vec![3]),
+                Stat::SetCells(0, vec![2, 3]),
+                Stat::PtrMove(1),
+                Stat::Input,
+                Stat::WhileNonZero(vec![Stat::DerefOp(Op::Add, -1)])
+            ])
+        );
+        assert_eq!(fold(",+"), parse(",+").unwrap());
+        assert_eq!(
+            fold("+>[,]::nop::"),
+            BrainFuck(vec![
+                Stat::SetCells(0, vec![1]),
+                Stat::PtrMove(1),
+                Stat::WhileNonZero(vec![Stat::Input]),
+                Stat::Asm("nop".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn stops_at_runaway_loops() {
+        assert_eq!(
+            fold("+[]<,"),
+            BrainFuck(vec![
+                Stat::SetCells(0, vec![1]),
+                Stat::WhileNonZero(vec![]),
+                Stat::PtrMove(-1),
+                Stat::Input
+            ])
+        );
+        assert_eq!(fold("<+,"), parse("<+,").unwrap());
+    }
+
+    #[test]
+    fn spans_folded_statements() {
+        let (bf, spans) = parse_spanned("+.>+,.").unwrap();
+        let mut remarks = Vec::new();
+        let (_, spans) = fold_prefix(bf, spans, 0, 100, &mut remarks);
+        let folded = Span { start: 0, end: 4 };
+        assert_eq!(
+            spans
+                .iter()
+                .map(|SpanTree(span, _)| *span)
+                .collect::<Vec<_>>(),
+            vec![
+                folded,
+                folded,
+                folded,
+                Span { start: 4, end: 5 },
+                Span { start: 5, end: 6 }
+            ]
+        );
+        assert_eq!(
+            remarks,
+            vec![Remark::at(
+                folded,
+                "ran 4 statements at compile time".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Optimisation of balanced loops (loops without net pointer movement).
+//!
+//! - Loops only adding constants to cells, where the counter (the current
+//!   cell) changes by an odd amount each iteration, run a number of times
+//!   proportional to the counter. So they are strength reduced to
+//!   multiply-adds into the other cells and a clear of the counter, for
+//!   example `[->+++<]` becomes `+=(1,3)=(0){0}` (and `[-]` becomes
+//!   `=(0){0}`).
+//! - Sets of cells that nothing else in a loop uses are invariant, so are
+//!   hoisted into a first iteration peeled from the loop (which runs once, as
+//!   the rest of the loop leaves the counter zero). For example `[>[-]<,]`
+//!   becomes `[=(1){0}[,]]`.
+//! - Loops with a counter of known value have a known trip count, so can be
+//!   unrolled (this is used by the cell value analysis).
+
+use std::collections::BTreeMap;
+
+use super::{count, Remark};
+use crate::intermediate::{BrainFuck, Op, Span, SpanTree, Spans, Stat, Stats};
+
+/// Statements with their spans.
+type Code = (Stats, Spans);
+
+/// Optimise the balanced loops in a program.
+pub fn optimise_loops(
+    BrainFuck(stats): BrainFuck,
+    spans: Spans,
+    remarks: &mut Vec<Remark>,
+) -> (BrainFuck, Spans) {
+    let (stats, spans) = loops(stats, spans, remarks);
+    (BrainFuck(stats), spans)
+}
+
+fn loops(stats: Stats, spans: Spans, remarks: &mut Vec<Remark>) -> Code {
+    let (mut result, mut result_spans) = (Vec::new(), Vec::new());
+    for (stat, SpanTree(span, inner)) in stats.into_iter().zip(spans) {
+        match stat {
+            Stat::WhileNonZero(body) => {
+                let (body, inner) = loops(body, inner, remarks);
+                let (stats, spans) = balanced_loop(body, inner, span, remarks);
+                result.extend(stats);
+                result_spans.extend(spans);
+            }
+            stat => {
+                result.push(stat);
+                result_spans.push(SpanTree(span, inner));
+            }
+        }
+    }
+    (result, result_spans)
+}
+
+/// Get the offset (from the start) of the pointer before each statement of
+/// straight line code, and the offset at the end.
+fn offsets(stats: &[Stat]) -> Option<(Vec<i32>, i32)> {
+    let mut offset = 0;
+    let mut result = Vec::new();
+    for stat in stats {
+        result.push(offset);
+        match stat {
+            Stat::PtrMove(i) => offset += i,
+            Stat::WhileNonZero(_) | Stat::ScanZero(_) | Stat::Asm(_) => return None,
+            _ => (),
+        }
+    }
+    Some((result, offset))
+}
+
+/// Get the cells (by offset from the pointer) a statement of straight line
+/// code reads or writes.
+fn used(stat: &Stat) -> Vec<i32> {
+    match stat {
+        Stat::DerefOp(_, _) | Stat::Input | Stat::Output => vec![0],
+        Stat::SetCells(start, cells) => (*start..*start + cells.len() as i32).collect(),
+        Stat::MulAdd(offset, _) => vec![0, *offset],
+        _ => vec![],
+    }
+}
+
+/// Get the amount added to each cell by an iteration of a loop body, if it
+/// is balanced and only adds constants.
+fn additions(body: &[Stat]) -> Option<BTreeMap<i32, i32>> {
+    let (offsets, 0) = offsets(body)? else {
+        return None;
+    };
+    let mut added = BTreeMap::new();
+    for (stat, offset) in body.iter().zip(offsets) {
+        match stat {
+            Stat::PtrMove(_) => (),
+            Stat::DerefOp(Op::Add, i) => *added.entry(offset).or_insert(0) += i,
+            _ => return None,
+        }
+    }
+    Some(added)
+}
+
+/// Get the number of iterations of a balanced loop, given the value of its
+/// counter (the current cell) on entry, if the loop only changes the counter
+/// by adding a constant.
+pub fn trip_count(body: &[Stat], counter: u8) -> Option<u32> {
+    let (offsets, 0) = offsets(body)? else {
+        return None;
+    };
+    let mut step = 0;
+    for (stat, offset) in body.iter().zip(offsets) {
+        match stat {
+            Stat::DerefOp(Op::Add, i) if offset == 0 => step += i,
+            stat if used(stat).iter().any(|cell| cell + offset == 0) => return None,
+            _ => (),
+        }
+    }
+    (0..256)
+        .find(|n| (i32::from(counter) + n * step).rem_euclid(256) == 0)
+        .map(|n| n as u32)
+}
+
+/// Optimise a loop (with an optimised body), returning its replacement.
+fn balanced_loop(body: Stats, inner: Spans, span: Span, remarks: &mut Vec<Remark>) -> Code {
+    if let Some(added) = additions(&body) {
+        let step = added.get(&0).copied().unwrap_or(0).rem_euclid(256);
+        if step % 2 == 1 {
+            // the counter reaches zero after counter * m iterations, where m
+            // is the inverse of -step (mod 256)
+            let m = (1..256)
+                .find(|m| (m * (256 - step)) % 256 == 1)
+                .expect("odd numbers are invertible mod 256");
+            let mut result: Stats = added
+                .into_iter()
+                .filter(|(offset, i)| *offset != 0 && i.rem_euclid(256) != 0)
+                .map(|(offset, i)| {
+                    Stat::MulAdd(offset, i32::from((i * m).rem_euclid(256) as u8 as i8))
+                })
+                .collect();
+            remarks.push(Remark::at(
+                span,
+                match result.len() {
+                    0 => "turned loop into a clear".to_string(),
+                    n => format!("turned loop into {} and a clear", count(n, "multiply-add")),
+                },
+            ));
+            result.push(Stat::SetCells(0, vec![0]));
+            let spans = vec![SpanTree(span, vec![]); result.len()];
+            return (result, spans);
+        }
+    }
+
+    match hoist_invariants(&body, &inner) {
+        Some(((mut peeled, mut peeled_spans), (rest, rest_spans))) => {
+            remarks.push(Remark::at(
+                span,
+                format!(
+                    "hoisted {} out of loop",
+                    count(peeled.len(), "invariant set")
+                ),
+            ));
+            let (stats, spans) = balanced_loop(rest, rest_spans, span, remarks);
+            peeled.extend(stats);
+            peeled_spans.extend(spans);
+            (
+                vec![Stat::WhileNonZero(peeled)],
+                vec![SpanTree(span, peeled_spans)],
+            )
+        }
+        None => (vec![Stat::WhileNonZero(body)], vec![SpanTree(span, inner)]),
+    }
+}
+
+/// Split sets of cells that nothing else uses (and are not the counter) from
+/// the body of a balanced loop, as sets from the start of the loop.
+fn hoist_invariants(body: &[Stat], inner: &[SpanTree]) -> Option<(Code, Code)> {
+    let (offsets, 0) = offsets(body)? else {
+        return None;
+    };
+    let uses = |i: usize| -> Vec<i32> {
+        used(&body[i])
+            .into_iter()
+            .map(|cell| cell + offsets[i])
+            .collect()
+    };
+    let invariant: Vec<usize> = (0..body.len())
+        .filter(|i| matches!(body[*i], Stat::SetCells(_, _)))
+        .filter(|i| {
+            let cells = uses(*i);
+            !cells.contains(&0)
+                && (0..body.len())
+                    .filter(|j| j != i)
+                    .all(|j| uses(j).iter().all(|cell| !cells.contains(cell)))
+        })
+        .collect();
+    if invariant.is_empty() {
+        return None;
+    }
+
+    let (mut hoisted, mut hoisted_spans) = (Vec::new(), Vec::new());
+    let mut rest: Vec<(Stat, SpanTree)> = Vec::new();
+    for (i, (stat, span)) in body.iter().zip(inner).enumerate() {
+        match stat {
+            Stat::SetCells(start, cells) if invariant.contains(&i) => {
+                hoisted.push(Stat::SetCells(start + offsets[i], cells.clone()));
+                hoisted_spans.push(span.clone());
+            }
+            // join the moves either side of a hoisted set
+            Stat::PtrMove(i) => match rest.last_mut() {
+                Some((Stat::PtrMove(last), _)) => *last += i,
+                _ => rest.push((Stat::PtrMove(*i), span.clone())),
+            },
+            stat => rest.push((stat.clone(), span.clone())),
+        }
+    }
+    let rest = rest
+        .into_iter()
+        .filter(|(stat, _)| *stat != Stat::PtrMove(0))
+        .unzip();
+    Some(((hoisted, hoisted_spans), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, parse_spanned};
+
+    fn optimise(source: &str) -> String {
+        let (bf, spans) = parse_spanned(source).unwrap();
+        optimise_loops(bf, spans, &mut Vec::new()).0.to_string()
+    }
+
+    #[test]
+    fn strength_reduces_counted_loops() {
+        assert_eq!(optimise(",[->+++>-<<]"), ",+=(1,3)+=(2,-1)=(0){0}\n");
+        assert_eq!(optimise(",[-]"), ",=(0){0}\n");
+        // the counter is decremented by 3, so reaches zero after 171 * counter
+        // iterations
+        assert_eq!(optimise(",[--->+<]"), ",+=(1,-85)=(0){0}\n");
+        assert_eq!(optimise(",[-->+<]"), ",[-->+<]\n");
+        assert_eq!(optimise(",[->.<]"), ",[->.<]\n");
+    }
+
+    #[test]
+    fn hoists_invariant_sets() {
+        assert_eq!(optimise(",[>>[-]<<->+<]"), ",[=(2){0}+=(1,1)=(0){0}]\n");
+        assert_eq!(optimise(",[>[-]<,]"), ",[=(1){0}[,]]\n");
+        assert_eq!(optimise(",[>[-]+<,]"), ",[>=(0){0}+<,]\n");
+    }
+
+    #[test]
+    fn remarks_on_changed_loops() {
+        let (bf, spans) = parse_spanned(",[>[-]<,]").unwrap();
+        let mut remarks = Vec::new();
+        let (_, spans) = optimise_loops(bf, spans, &mut remarks);
+        let outer = Span { start: 1, end: 9 };
+        assert_eq!(
+            remarks,
+            vec![
+                Remark::at(
+                    Span { start: 3, end: 6 },
+                    "turned loop into a clear".to_string()
+                ),
+                Remark::at(outer, "hoisted 1 invariant set out of loop".to_string())
+            ]
+        );
+        // the peeled loop and the loop left in it share the original span
+        assert_eq!(
+            spans[1],
+            SpanTree(
+                outer,
+                vec![
+                    SpanTree(Span { start: 3, end: 6 }, vec![]),
+                    SpanTree(outer, vec![SpanTree(Span { start: 7, end: 8 }, vec![])])
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn counts_trips() {
+        let BrainFuck(body) = parse("->.<").unwrap();
+        assert_eq!(trip_count(&body, 3), Some(3));
+        let BrainFuck(body) = parse("--").unwrap();
+        assert_eq!(trip_count(&body, 3), None);
+        let BrainFuck(body) = parse("-,").unwrap();
+        assert_eq!(trip_count(&body, 3), None);
+    }
+}
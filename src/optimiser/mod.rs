@@ -0,0 +1,104 @@
+//! Architecture neutral optimisations on the intermediate representation.
+//!
+//! Each pass keeps the spans of the statements it produces (mirroring the
+//! program), so it can report what it changed as remarks at locations in the
+//! source.
+
+mod constant;
+mod loops;
+mod rules;
+mod scan;
+mod values;
+
+use clap::ArgEnum;
+
+pub use rules::{parse_rules, Rule};
+
+use crate::intermediate::{BrainFuck, Span, Spans};
+
+/// The optimiser passes, in the order they are run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum Pass {
+    Constant,
+    Rules,
+    Scan,
+    Loops,
+    Values,
+}
+
+/// A change made by a pass, at a span in the source unless it summarises
+/// changes throughout the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remark {
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Constant => "constant",
+            Pass::Rules => "rules",
+            Pass::Scan => "scan",
+            Pass::Loops => "loops",
+            Pass::Values => "values",
+        }
+    }
+}
+
+/// Describe a number of things, such as "1 loop" or "2 loops".
+fn count(n: usize, thing: &str) -> String {
+    format!("{} {}{}", n, thing, if n == 1 { "" } else { "s" })
+}
+
+impl Remark {
+    fn at(span: Span, message: String) -> Self {
+        Remark {
+            span: Some(span),
+            message,
+        }
+    }
+}
+
+/// Optimise a program (with the spans of its statements), given the cells
+/// before and after the initial pointer position of its zeroed tape (if parts
+/// of the program can be run at compile time), and the rules for the target.
+/// After each pass the program and the pass's remarks are given to an
+/// observer. The optimised program is returned with its spans.
+pub fn optimise(
+    bf: BrainFuck,
+    spans: Spans,
+    tape: Option<(u32, u32)>,
+    rules: &[Rule],
+    observe: &mut dyn FnMut(Pass, &BrainFuck, Vec<Remark>),
+) -> (BrainFuck, Spans) {
+    let mut program = (bf, spans);
+    for pass in [
+        Pass::Constant,
+        Pass::Rules,
+        Pass::Scan,
+        Pass::Loops,
+        Pass::Values,
+    ] {
+        let (bf, spans) = program;
+        let mut remarks = Vec::new();
+        program = match (pass, tape) {
+            (Pass::Constant, Some((pre, post))) => {
+                constant::fold_prefix(bf, spans, pre, post, &mut remarks)
+            }
+            (Pass::Constant, None) => (bf, spans),
+            (Pass::Rules, _) => rules::apply_rules(bf, spans, rules, &mut remarks),
+            (Pass::Scan, _) => scan::scan_loops(bf, spans, &mut remarks),
+            (Pass::Loops, _) => loops::optimise_loops(bf, spans, &mut remarks),
+            (Pass::Values, _) => values::propagate_values(bf, spans, tape.is_some(), &mut remarks),
+        };
+        observe(pass, &program.0, remarks);
+    }
+    program
+}
+
+/// Optimise part of a program run on a tape left by earlier parts (such as a
+/// line in the repl), so nothing is known about the cells before or after.
+pub fn optimise_part(bf: BrainFuck, spans: Spans, rules: &[Rule]) -> (BrainFuck, Spans) {
+    optimise(bf, spans, None, rules, &mut |_, _, _| ())
+}
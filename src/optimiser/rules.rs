@@ -0,0 +1,434 @@
+//! User rules rewriting patterns of statements into inserts for a target, so
+//! idioms can be given better code without changing the compiler.
+//!
+//! A rule file contains rules of the form:
+//! ```text
+//! # add the current cell to a cell to the left
+//! rule add-left c99 [-<($d)+>($d)]
+//! ptr[-$d] += *ptr;
+//! *ptr = 0;
+//! end
+//! ```
+//! Patterns are written like brainfuck, with amounts given in brackets (so
+//! `<(2)` moves left by two, and `-(3)` subtracts three). An amount is either a
+//! number or a capture (`$name`), which matches any value, but only the same
+//! value where it is repeated. The lines up to `end` are inserted in place of
+//! the matched statements, with `$name` replaced by the captured value (and
+//! `$$` by `$`). Lines outside of rules that are blank or start with `#` are
+//! ignored.
+//!
+//! Before matching, runs of pointer moves and additions are combined, so `<<`
+//! matches `<($d)` with `$d` as 2.
+
+use std::{collections::HashMap, fmt::Display};
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{i32, multispace0, one_of},
+    combinator::{map, opt},
+    multi::many0,
+    sequence::{delimited, pair, preceded},
+    IResult,
+};
+
+use super::Remark;
+use crate::intermediate::{BrainFuck, Op, Span, SpanTree, Spans, Stat, Stats};
+
+/// An amount in a pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Fixed(i32),
+    Capture(String),
+}
+
+/// A pattern matching a statement, amounts are negated where the direction
+/// is left or subtraction.
+#[derive(Debug, Clone, PartialEq)]
+enum Pattern {
+    PtrMove(i32, Value),
+    DerefOp(Op, i32, Value),
+    Output,
+    Input,
+    WhileNonZero(Vec<Pattern>),
+}
+
+/// A rule replacing statements matching a pattern with an insert, when
+/// compiling for a target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub target: String,
+    pattern: Vec<Pattern>,
+    template: String,
+}
+
+/// A problem with a rule file, at a line (from 1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+/// The values of captures in a match.
+type Captures = HashMap<String, i32>;
+
+fn capture(input: &str) -> IResult<&str, &str> {
+    preceded(
+        tag("$"),
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    )(input)
+}
+
+fn value(input: &str) -> IResult<&str, Value> {
+    alt((
+        map(capture, |name| Value::Capture(name.to_string())),
+        map(i32, Value::Fixed),
+    ))(input)
+}
+
+/// Parse a pattern command, with an optional amount in brackets.
+fn command(input: &str) -> IResult<&str, Pattern> {
+    map(
+        pair(one_of("<>+-*/%"), opt(delimited(tag("("), value, tag(")")))),
+        |(symbol, amount)| {
+            let amount = amount.unwrap_or(Value::Fixed(1));
+            match symbol {
+                '>' => Pattern::PtrMove(1, amount),
+                '<' => Pattern::PtrMove(-1, amount),
+                '+' => Pattern::DerefOp(Op::Add, 1, amount),
+                '-' => Pattern::DerefOp(Op::Add, -1, amount),
+                '*' => Pattern::DerefOp(Op::Mul, 1, amount),
+                '/' => Pattern::DerefOp(Op::Div, 1, amount),
+                _ => Pattern::DerefOp(Op::Mod, 1, amount),
+            }
+        },
+    )(input)
+}
+
+fn patterns(input: &str) -> IResult<&str, Vec<Pattern>> {
+    many0(preceded(
+        multispace0,
+        alt((
+            command,
+            map(tag("."), |_| Pattern::Output),
+            map(tag(","), |_| Pattern::Input),
+            map(
+                delimited(tag("["), patterns, preceded(multispace0, tag("]"))),
+                Pattern::WhileNonZero,
+            ),
+        )),
+    ))(input)
+}
+
+/// Get the names of the captures in some patterns.
+fn captures(patterns: &[Pattern]) -> Vec<&str> {
+    patterns
+        .iter()
+        .flat_map(|pattern| match pattern {
+            Pattern::PtrMove(_, Value::Capture(name))
+            | Pattern::DerefOp(_, _, Value::Capture(name)) => vec![name.as_str()],
+            Pattern::WhileNonZero(body) => captures(body),
+            _ => vec![],
+        })
+        .collect()
+}
+
+/// Substitute captured values into a template, returning the name of any
+/// capture that is missing.
+fn substitute(template: &str, captures: &Captures) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = after;
+        } else if let Ok((after, name)) = capture(rest) {
+            let value = captures.get(name).ok_or_else(|| name.to_string())?;
+            result.push_str(&value.to_string());
+            rest = after;
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Parse the rules in a rule file.
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>, Error> {
+    let mut rules = Vec::new();
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+    while let Some((line, header)) = lines.next() {
+        let error = |message: &str| Error {
+            line,
+            message: message.to_string(),
+        };
+        let header = header.trim();
+        if header.is_empty() || header.starts_with('#') {
+            continue;
+        }
+
+        let mut words = header.splitn(4, char::is_whitespace);
+        let (Some("rule"), Some(name), Some(target), Some(source)) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            return Err(error("expected `rule <name> <target> <pattern>`"));
+        };
+        let pattern = match patterns(source) {
+            Ok((rest, pattern)) if rest.trim().is_empty() && !pattern.is_empty() => pattern,
+            Ok((rest, _)) if !rest.trim().is_empty() => {
+                return Err(error(&format!("unexpected `{}` in pattern", rest.trim())))
+            }
+            _ => return Err(error("the pattern is empty")),
+        };
+
+        let mut template = Vec::new();
+        loop {
+            match lines.next() {
+                Some((_, "end")) => break,
+                Some((_, text)) => template.push(text),
+                None => return Err(error("rule has no `end`")),
+            }
+        }
+        let template = template.join("\n");
+
+        // check every capture used is in the pattern
+        let captured = captures(&pattern)
+            .into_iter()
+            .map(|name| (name.to_string(), 0))
+            .collect();
+        if let Err(name) = substitute(&template, &captured) {
+            return Err(error(&format!(
+                "`${}` is not captured by the pattern",
+                name
+            )));
+        }
+
+        rules.push(Rule {
+            name: name.to_string(),
+            target: target.to_string(),
+            pattern,
+            template,
+        })
+    }
+    Ok(rules)
+}
+
+/// Match an amount against a value, with a sign for its direction. Cell
+/// values wrap, so are compared modulo 256.
+fn match_value(
+    value: &Value,
+    sign: i32,
+    amount: i32,
+    wraps: bool,
+    captures: &mut Captures,
+) -> bool {
+    let amount = if wraps {
+        (sign * amount).rem_euclid(256)
+    } else {
+        sign * amount
+    };
+    let expected = match value {
+        Value::Fixed(fixed) => *fixed,
+        Value::Capture(name) => *captures.entry(name.clone()).or_insert(amount),
+    };
+    if wraps {
+        expected.rem_euclid(256) == amount
+    } else {
+        expected == amount
+    }
+}
+
+/// Match patterns against the start of some statements, returning the number
+/// of statements matched.
+fn match_patterns(patterns: &[Pattern], stats: &[Stat], captures: &mut Captures) -> Option<usize> {
+    if stats.len() < patterns.len() {
+        return None;
+    }
+    for (pattern, stat) in patterns.iter().zip(stats) {
+        let matched = match (pattern, stat) {
+            (Pattern::PtrMove(sign, value), Stat::PtrMove(i)) => {
+                match_value(value, *sign, *i, false, captures)
+            }
+            (Pattern::DerefOp(op, sign, value), Stat::DerefOp(stat_op, i)) => {
+                op == stat_op && match_value(value, *sign, *i, true, captures)
+            }
+            (Pattern::Output, Stat::Output) | (Pattern::Input, Stat::Input) => true,
+            (Pattern::WhileNonZero(patterns), Stat::WhileNonZero(body)) => {
+                match_patterns(patterns, body, captures) == Some(body.len())
+            }
+            _ => false,
+        };
+        if !matched {
+            return None;
+        }
+    }
+    Some(patterns.len())
+}
+
+/// Combine runs of pointer moves and of additions (with spans covering the
+/// run).
+fn combine_runs(stats: Stats, spans: Spans) -> (Stats, Spans) {
+    let mut result: Vec<(Stat, SpanTree)> = Vec::new();
+    for (stat, SpanTree(span, inner)) in stats.into_iter().zip(spans) {
+        let stat = match stat {
+            Stat::WhileNonZero(body) => {
+                let (body, inner) = combine_runs(body, inner);
+                result.push((Stat::WhileNonZero(body), SpanTree(span, inner)));
+                continue;
+            }
+            stat => stat,
+        };
+        match (result.last_mut(), &stat) {
+            (Some((Stat::PtrMove(last), SpanTree(last_span, _))), Stat::PtrMove(i))
+            | (
+                Some((Stat::DerefOp(Op::Add, last), SpanTree(last_span, _))),
+                Stat::DerefOp(Op::Add, i),
+            ) => {
+                *last += i;
+                last_span.end = span.end;
+            }
+            _ => result.push((stat, SpanTree(span, inner))),
+        }
+    }
+    result.into_iter().unzip()
+}
+
+/// Replace statements matching the rules with inserts, trying each rule in
+/// order at each statement.
+fn rewrite(
+    stats: Stats,
+    spans: Spans,
+    rules: &[Rule],
+    remarks: &mut Vec<Remark>,
+) -> (Stats, Spans) {
+    let (mut result, mut result_spans) = (Vec::new(), Vec::new());
+    let mut i = 0;
+    while i < stats.len() {
+        let matched = rules.iter().find_map(|rule| {
+            let mut captures = Captures::new();
+            let len = match_patterns(&rule.pattern, &stats[i..], &mut captures)?;
+            Some((rule, len, captures))
+        });
+        if let Some((rule, len, captures)) = matched {
+            let span = Span {
+                start: spans[i].0.start,
+                end: spans[i + len - 1].0.end,
+            };
+            remarks.push(Remark::at(
+                span,
+                format!("replaced statements with an insert from rule {}", rule.name),
+            ));
+            let insert =
+                substitute(&rule.template, &captures).expect("templates are checked when parsed");
+            result.push(Stat::Asm(insert));
+            result_spans.push(SpanTree(span, vec![]));
+            i += len;
+            continue;
+        }
+
+        match &stats[i] {
+            Stat::WhileNonZero(body) => {
+                let (body, inner) = rewrite(body.clone(), spans[i].1.clone(), rules, remarks);
+                result.push(Stat::WhileNonZero(body));
+                result_spans.push(SpanTree(spans[i].0, inner));
+            }
+            stat => {
+                result.push(stat.clone());
+                result_spans.push(spans[i].clone());
+            }
+        }
+        i += 1;
+    }
+    (result, result_spans)
+}
+
+/// Apply rules (for the target being compiled to) to a program.
+pub fn apply_rules(
+    BrainFuck(stats): BrainFuck,
+    spans: Spans,
+    rules: &[Rule],
+    remarks: &mut Vec<Remark>,
+) -> (BrainFuck, Spans) {
+    if rules.is_empty() {
+        return (BrainFuck(stats), spans);
+    }
+    let (stats, spans) = combine_runs(stats, spans);
+    let (stats, spans) = rewrite(stats, spans, rules, remarks);
+    (BrainFuck(stats), spans)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_spanned;
+
+    const RULES: &str = "# moves
+rule add-left c99 [-<($d)+>($d)]
+ptr[-$d] += *ptr;
+*ptr = 0;
+end
+
+rule triple c99 [-] +(3)
+*ptr = 3; /* $$3 */
+end
+";
+
+    fn apply(source: &str) -> String {
+        let (bf, spans) = parse_spanned(source).unwrap();
+        let rules = parse_rules(RULES).unwrap();
+        apply_rules(bf, spans, &rules, &mut Vec::new())
+            .0
+            .to_string()
+    }
+
+    #[test]
+    fn replaces_matches_with_inserts() {
+        assert_eq!(
+            apply(",[-<<+>>],[-<+>]"),
+            ",::ptr[-2] += *ptr;\n*ptr = 0;::,::ptr[-1] += *ptr;\n*ptr = 0;::\n"
+        );
+        assert_eq!(apply("+[[-]+++.]"), "+[::*ptr = 3; /* $3 */::.]\n");
+    }
+
+    #[test]
+    fn requires_repeated_captures_to_match() {
+        assert_eq!(apply(",[-<<+>]"), ",[-<(-2)+>]\n");
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert_eq!(
+            parse_rules("\nrule bad c99 [->+<]\n$x\nend"),
+            Err(Error {
+                line: 2,
+                message: "`$x` is not captured by the pattern".to_string()
+            })
+        );
+        assert_eq!(
+            parse_rules("rule bad c99 [->+<"),
+            Err(Error {
+                line: 1,
+                message: "unexpected `[->+<` in pattern".to_string()
+            })
+        );
+        assert_eq!(
+            parse_rules("rule empty c99 [-]\n"),
+            Err(Error {
+                line: 1,
+                message: "rule has no `end`".to_string()
+            })
+        );
+        assert!(parse_rules("pattern [-]").is_err());
+    }
+}
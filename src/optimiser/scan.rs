@@ -0,0 +1,85 @@
+//! Replacement of loops that only move the pointer (searching for a zero
+//! cell) with a scan, for example `[>>]` becomes `?(2)`.
+
+use super::Remark;
+use crate::intermediate::{BrainFuck, SpanTree, Spans, Stat, Stats};
+
+/// Replace loops of only pointer moves with scans.
+pub fn scan_loops(
+    BrainFuck(stats): BrainFuck,
+    spans: Spans,
+    remarks: &mut Vec<Remark>,
+) -> (BrainFuck, Spans) {
+    let (stats, spans) = scan_stats(stats, spans, remarks);
+    (BrainFuck(stats), spans)
+}
+
+fn scan_stats(stats: Stats, spans: Spans, remarks: &mut Vec<Remark>) -> (Stats, Spans) {
+    stats
+        .into_iter()
+        .zip(spans)
+        .map(|(stat, SpanTree(span, inner))| match stat {
+            Stat::WhileNonZero(body) => match stride(&body) {
+                Some(stride) => {
+                    remarks.push(Remark::at(
+                        span,
+                        format!("turned loop into a scan with stride {}", stride),
+                    ));
+                    (Stat::ScanZero(stride), SpanTree(span, vec![]))
+                }
+                None => {
+                    let (body, inner) = scan_stats(body, inner, remarks);
+                    (Stat::WhileNonZero(body), SpanTree(span, inner))
+                }
+            },
+            stat => (stat, SpanTree(span, inner)),
+        })
+        .unzip()
+}
+
+/// Get the net movement of a loop body of only pointer moves, if it moves.
+fn stride(body: &[Stat]) -> Option<i32> {
+    body.iter()
+        .try_fold(0, |total, stat| match stat {
+            Stat::PtrMove(i) => Some(total + i),
+            _ => None,
+        })
+        .filter(|stride| *stride != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        intermediate::{Op, Span},
+        parser::{parse, parse_spanned},
+    };
+
+    fn scan(source: &str, remarks: &mut Vec<Remark>) -> BrainFuck {
+        let (bf, spans) = parse_spanned(source).unwrap();
+        scan_loops(bf, spans, remarks).0
+    }
+
+    #[test]
+    fn replaces_pointer_loops() {
+        let mut remarks = Vec::new();
+        assert_eq!(
+            scan("[>][<<][>><]+[-[<]]", &mut remarks),
+            BrainFuck(vec![
+                Stat::ScanZero(1),
+                Stat::ScanZero(-2),
+                Stat::ScanZero(1),
+                Stat::DerefOp(Op::Add, 1),
+                Stat::WhileNonZero(vec![Stat::DerefOp(Op::Add, -1), Stat::ScanZero(-1)])
+            ])
+        );
+        assert_eq!(
+            remarks[3],
+            Remark::at(
+                Span { start: 15, end: 18 },
+                "turned loop into a scan with stride -1".to_string()
+            )
+        );
+        assert_eq!(scan("[><]", &mut remarks), parse("[><]").unwrap());
+    }
+}
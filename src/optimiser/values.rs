@@ -0,0 +1,509 @@
+//! Cell value analysis, and the optimisations it enables.
+//!
+//! Within straight line code (up to a loop or scan that may move the pointer,
+//! or an insert) the offset of the pointer from the start of the code is known, so
+//! the value of each cell can be tracked while it is constant. Clear loops
+//! (`[-]`) only change the current cell, so are part of straight line code.
+//! This is used to:
+//! - Remove loops whose condition cell is provably zero on entry, for example
+//!   the second loop in `[>+<-][-]`.
+//! - Replace operations on cells of known value with a set, so `[-]+++`
+//!   becomes `=(0){3}`.
+//! - Remove dead stores, which are overwritten before being read (`+(5),`
+//!   becomes `,`) or are never read before the end of the program.
+//! - Unroll short loops whose counter is known, so have a known trip count
+//!   (`=(0){3}[>.<-]` becomes three copies of the body).
+//! - Output known cells as constant output, merging outputs only separated by
+//!   changes to the tape, so `=(0){65}.+.` becomes `=(0){66}"AB"`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::{count, loops::trip_count, Remark};
+use crate::intermediate::{BrainFuck, Op, Span, SpanTree, Spans, Stat, Stats};
+
+/// The most statements a loop is unrolled into.
+const UNROLL_LIMIT: usize = 64;
+
+/// The values of cells, by offset from the pointer at the start of the
+/// current straight line code.
+struct Cells {
+    /// The offset of the pointer.
+    offset: i32,
+    /// Cells that have been changed, and their value if known.
+    values: HashMap<i32, Option<u8>>,
+    /// If the cells not present in values are zero.
+    zeroed: bool,
+}
+
+/// The cells (by offset) whose values are never read before being
+/// overwritten.
+struct Dead {
+    /// If no cell is read again, other than those in cells.
+    all: bool,
+    cells: HashSet<i32>,
+}
+
+impl Cells {
+    fn new(zeroed: bool) -> Self {
+        Cells {
+            offset: 0,
+            values: HashMap::new(),
+            zeroed,
+        }
+    }
+
+    fn get(&self, offset: i32) -> Option<u8> {
+        match self.values.get(&offset) {
+            Some(value) => *value,
+            None if self.zeroed => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, offset: i32, value: Option<u8>) {
+        self.values.insert(offset, value);
+    }
+}
+
+impl Dead {
+    fn contains(&self, offset: i32) -> bool {
+        self.all != self.cells.contains(&offset)
+    }
+
+    fn read(&mut self, offset: i32) {
+        if self.all {
+            self.cells.insert(offset);
+        } else {
+            self.cells.remove(&offset);
+        }
+    }
+
+    fn write(&mut self, offset: i32) {
+        if self.all {
+            self.cells.remove(&offset);
+        } else {
+            self.cells.insert(offset);
+        }
+    }
+
+    /// Assume every cell may be read.
+    fn clear(&mut self) {
+        self.all = false;
+        self.cells.clear()
+    }
+}
+
+/// Check if a statement is a loop setting the current cell to zero (adding an
+/// odd number always reaches zero).
+fn is_clear(stat: &Stat) -> bool {
+    matches!(stat, Stat::WhileNonZero(body) if matches!(body[..], [Stat::DerefOp(Op::Add, i)] if i % 2 != 0))
+}
+
+fn apply(op: &Op, value: u8, i: i32) -> u8 {
+    let i = i.rem_euclid(256) as u8;
+    match op {
+        Op::Add => value.wrapping_add(i),
+        Op::Mul => value.wrapping_mul(i),
+        Op::Div => value.checked_div(i).unwrap_or(0),
+        Op::Mod => value.checked_rem(i).unwrap_or(0),
+    }
+}
+
+/// The changes made, with remarks on those at a single location.
+struct Report<'a> {
+    remarks: &'a mut Vec<Remark>,
+    /// Statements simplified using known values.
+    known: usize,
+    /// Dead stores removed (or trimmed).
+    dead: usize,
+}
+
+/// Optimise a program using the values of cells. A whole program starts
+/// from a zeroed tape, and no cell is read after it ends, otherwise nothing is
+/// known about the tape before or after.
+pub fn propagate_values(
+    BrainFuck(stats): BrainFuck,
+    spans: Spans,
+    whole: bool,
+    remarks: &mut Vec<Remark>,
+) -> (BrainFuck, Spans) {
+    let mut report = Report {
+        remarks,
+        known: 0,
+        dead: 0,
+    };
+    let (stats, spans) = optimise_stats(
+        stats,
+        spans,
+        Cells::new(whole),
+        Dead {
+            all: whole,
+            cells: HashSet::new(),
+        },
+        &mut report,
+    );
+    let Report { known, dead, .. } = report;
+    if known > 0 {
+        remarks.push(Remark {
+            span: None,
+            message: format!(
+                "simplified {} using known cell values",
+                count(known, "statement")
+            ),
+        })
+    }
+    if dead > 0 {
+        remarks.push(Remark {
+            span: None,
+            message: format!("removed {}", count(dead, "dead store")),
+        })
+    }
+    (BrainFuck(stats), spans)
+}
+
+/// Optimise statements, given the cells known at the start and those dead at
+/// the end.
+fn optimise_stats(
+    stats: Stats,
+    spans: Spans,
+    cells: Cells,
+    dead: Dead,
+    report: &mut Report,
+) -> (Stats, Spans) {
+    let propagated = propagate(stats, spans, cells, report);
+    eliminate_stores(merge_outputs(propagated), dead, report)
+}
+
+/// Get the number of times to unroll a loop, if it should be unrolled given
+/// the value of its counter.
+fn unroll(body: &[Stat], counter: Option<u8>) -> Option<usize> {
+    let trips = trip_count(body, counter?)? as usize;
+    (trips * body.len() <= UNROLL_LIMIT).then_some(trips)
+}
+
+/// Remove loops that are never entered and set cells of known value,
+/// returning the statements with the offset of the pointer before each.
+fn propagate(
+    stats: Stats,
+    spans: Spans,
+    mut cells: Cells,
+    report: &mut Report,
+) -> Vec<(i32, Stat, SpanTree)> {
+    let mut result = Vec::new();
+    let mut pending: VecDeque<_> = stats.into_iter().zip(spans).collect();
+    while let Some((stat, SpanTree(span, inner))) = pending.pop_front() {
+        let offset = cells.offset;
+        let mut inner = inner;
+        let stat = match stat {
+            Stat::PtrMove(i) => {
+                cells.offset += i;
+                Stat::PtrMove(i)
+            }
+            Stat::DerefOp(op, i) => match cells.get(offset) {
+                Some(value) => {
+                    let value = apply(&op, value, i);
+                    cells.set(offset, Some(value));
+                    report.known += 1;
+                    Stat::SetCells(0, vec![value])
+                }
+                None => Stat::DerefOp(op, i),
+            },
+            Stat::Input => {
+                cells.set(offset, None);
+                Stat::Input
+            }
+            Stat::MulAdd(_, _) if cells.get(offset) == Some(0) => {
+                report.known += 1;
+                continue;
+            }
+            Stat::MulAdd(target, factor) => match (cells.get(offset), cells.get(offset + target)) {
+                (Some(value), Some(cell)) => {
+                    let value = apply(&Op::Add, cell, i32::from(value) * factor);
+                    cells.set(offset + target, Some(value));
+                    report.known += 1;
+                    Stat::SetCells(target, vec![value])
+                }
+                _ => {
+                    cells.set(offset + target, None);
+                    Stat::MulAdd(target, factor)
+                }
+            },
+            Stat::SetCells(start, values) => {
+                for (i, value) in values.iter().enumerate() {
+                    cells.set(offset + start + i as i32, Some(*value))
+                }
+                Stat::SetCells(start, values)
+            }
+            Stat::WhileNonZero(_) if cells.get(offset) == Some(0) => {
+                report.remarks.push(Remark::at(
+                    span,
+                    "removed loop that is never entered".to_string(),
+                ));
+                continue;
+            }
+            stat @ Stat::WhileNonZero(_) if is_clear(&stat) => {
+                let known = cells.get(offset).is_some();
+                cells.set(offset, Some(0));
+                if known {
+                    report.known += 1;
+                    inner = vec![];
+                    Stat::SetCells(0, vec![0])
+                } else {
+                    stat
+                }
+            }
+            Stat::WhileNonZero(body) => {
+                if let Some(trips) = unroll(&body, cells.get(offset)) {
+                    report.remarks.push(Remark::at(
+                        span,
+                        format!("unrolled loop {}", count(trips, "time")),
+                    ));
+                    for _ in 0..trips {
+                        for stat in body.iter().cloned().zip(inner.iter().cloned()).rev() {
+                            pending.push_front(stat)
+                        }
+                    }
+                    continue;
+                }
+                // the loop may move the pointer, so start again from it
+                cells = Cells::new(false);
+                cells.set(0, Some(0));
+                let (body, body_spans) = optimise_stats(
+                    body,
+                    inner,
+                    Cells::new(false),
+                    Dead {
+                        all: false,
+                        cells: HashSet::new(),
+                    },
+                    report,
+                );
+                inner = body_spans;
+                Stat::WhileNonZero(body)
+            }
+            Stat::ScanZero(_) if cells.get(offset) == Some(0) => {
+                report.remarks.push(Remark::at(
+                    span,
+                    "removed scan starting at a zero cell".to_string(),
+                ));
+                continue;
+            }
+            Stat::ScanZero(stride) => {
+                cells = Cells::new(false);
+                cells.set(0, Some(0));
+                Stat::ScanZero(stride)
+            }
+            Stat::Asm(asm) => {
+                cells = Cells::new(false);
+                Stat::Asm(asm)
+            }
+            Stat::Output => match cells.get(offset) {
+                Some(value) => {
+                    report.known += 1;
+                    Stat::OutputConst(vec![value])
+                }
+                None => Stat::Output,
+            },
+            stat @ Stat::OutputConst(_) => stat,
+        };
+        result.push((offset, stat, SpanTree(span, inner)));
+    }
+    result
+}
+
+/// Merge constant outputs only separated by statements changing the tape (not
+/// input, loops or inserts) into a single constant output, in place of the
+/// last.
+fn merge_outputs(stats: Vec<(i32, Stat, SpanTree)>) -> Vec<(i32, Stat, SpanTree)> {
+    let mut result: Vec<(i32, Stat, SpanTree)> = Vec::new();
+    // the index of the last constant output, while it can be merged
+    let mut last = None;
+    for (offset, stat, SpanTree(span, inner)) in stats {
+        match stat {
+            Stat::OutputConst(bytes) => {
+                let (bytes, span) = match last.map(|i| result.remove(i)) {
+                    Some((_, Stat::OutputConst(before), SpanTree(before_span, _))) => (
+                        [before, bytes].concat(),
+                        Span {
+                            start: before_span.start.min(span.start),
+                            end: before_span.end.max(span.end),
+                        },
+                    ),
+                    _ => (bytes, span),
+                };
+                last = Some(result.len());
+                result.push((offset, Stat::OutputConst(bytes), SpanTree(span, inner)));
+                continue;
+            }
+            // output can be moved past changes to the tape
+            Stat::PtrMove(_) | Stat::DerefOp(_, _) | Stat::SetCells(_, _) | Stat::MulAdd(_, _) => {}
+            _ => last = None,
+        }
+        result.push((offset, stat, SpanTree(span, inner)));
+    }
+    result
+}
+
+/// Remove stores to dead cells, working back from the end of the statements.
+fn eliminate_stores(
+    stats: Vec<(i32, Stat, SpanTree)>,
+    mut dead: Dead,
+    report: &mut Report,
+) -> (Stats, Spans) {
+    let mut result = Vec::new();
+    // if the statements end the program, pointer moves after the last
+    // statement kept using the tape have no effect
+    let mut trailing = dead.all;
+    for (offset, stat, span) in stats.into_iter().rev() {
+        match stat {
+            Stat::PtrMove(_) if trailing => continue,
+            Stat::PtrMove(_) => (),
+            // the value of the current cell is kept for backends writing the
+            // output with it
+            Stat::OutputConst(_) => dead.read(offset),
+            Stat::DerefOp(_, _) if dead.contains(offset) => {
+                report.dead += 1;
+                continue;
+            }
+            Stat::DerefOp(_, _) | Stat::Output => dead.read(offset),
+            Stat::MulAdd(target, _) if dead.contains(offset + target) => {
+                report.dead += 1;
+                continue;
+            }
+            Stat::MulAdd(target, _) => {
+                dead.read(offset + target);
+                dead.read(offset)
+            }
+            Stat::Input => dead.write(offset),
+            Stat::SetCells(start, values) => {
+                let first = offset + start;
+                let mut live = (0..values.len()).filter(|i| !dead.contains(first + *i as i32));
+                let range = live
+                    .next()
+                    .map(|from| (from, live.next_back().unwrap_or(from)));
+                for i in 0..values.len() {
+                    dead.write(first + i as i32)
+                }
+                if range.map(|(from, to)| to + 1 - from) != Some(values.len()) {
+                    report.dead += 1;
+                }
+                if let Some((from, to)) = range {
+                    trailing = false;
+                    result.push((
+                        Stat::SetCells(start + from as i32, values[from..=to].to_vec()),
+                        span,
+                    ))
+                }
+                continue;
+            }
+            Stat::WhileNonZero(_) if is_clear(&stat) => {
+                if dead.contains(offset) {
+                    report.dead += 1;
+                    continue;
+                }
+                dead.write(offset);
+            }
+            Stat::WhileNonZero(_) | Stat::ScanZero(_) | Stat::Asm(_) => dead.clear(),
+        }
+        trailing &= matches!(stat, Stat::PtrMove(_));
+        result.push((stat, span));
+    }
+    result.into_iter().rev().unzip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_spanned;
+
+    fn optimise(source: &str) -> String {
+        let (bf, spans) = parse_spanned(source).unwrap();
+        propagate_values(bf, spans, true, &mut Vec::new())
+            .0
+            .to_string()
+    }
+
+    #[test]
+    fn removes_loops_never_entered() {
+        assert_eq!(optimise(",[>+<-][-][>]."), ",[>+<-]\"\\x00\"\n");
+        assert_eq!(optimise("[-],."), ",.\n");
+    }
+
+    #[test]
+    fn sets_known_cells() {
+        assert_eq!(optimise(",[-]++.,"), ",=(0){2}\"\\x02\",\n");
+        assert_eq!(optimise(",>++<.>."), ",>=(0){2}<.>\"\\x02\"\n");
+    }
+
+    #[test]
+    fn removes_dead_stores() {
+        assert_eq!(optimise(",+++++,."), ",,.\n");
+        assert_eq!(optimise(",+[-]>,<."), ",[-]>,<\"\\x00\"\n");
+        assert_eq!(optimise(",.>+++<-"), ",.\n");
+        assert_eq!(optimise(",[+>,<]"), ",[+>,<]\n");
+        // moves before the last use of the tape are kept
+        assert_eq!(optimise(">,-++.++>--<<+>-+.-"), ">,-++.++><<>-+.\n");
+    }
+
+    #[test]
+    fn merges_known_outputs() {
+        assert_eq!(
+            optimise(&format!(",[-]{}.+.+.", "+".repeat(65))),
+            ",=(0){67}\"ABC\"\n"
+        );
+        // output is not moved past input
+        assert_eq!(
+            optimise("+.>++.,<."),
+            "=(0){1}>=(0){2}\"\\x01\\x02\",<\"\\x01\"\n"
+        );
+    }
+
+    #[test]
+    fn unrolls_known_loops() {
+        assert_eq!(optimise(",>+++[<.>-]"), ",><.><.><.\n");
+        // the counter never reaches zero
+        assert_eq!(optimise(",>+++[--<.>]"), ",>=(0){3}[--<.>]\n");
+    }
+
+    #[test]
+    fn folds_multiply_adds() {
+        let stats = vec![
+            Stat::DerefOp(Op::Add, 3),
+            Stat::MulAdd(1, 2),
+            Stat::SetCells(0, vec![0]),
+            Stat::PtrMove(1),
+            Stat::Output,
+        ];
+        let spans = vec![SpanTree(Span { start: 0, end: 0 }, vec![]); stats.len()];
+        assert_eq!(
+            propagate_values(BrainFuck(stats), spans, true, &mut Vec::new())
+                .0
+                .to_string(),
+            "=(1){6}>\"\\x06\"\n"
+        );
+    }
+
+    #[test]
+    fn remarks_on_changes() {
+        let (bf, spans) = parse_spanned(",[-]+++>[.]<.").unwrap();
+        let mut remarks = Vec::new();
+        propagate_values(bf, spans, true, &mut remarks);
+        assert_eq!(
+            remarks,
+            vec![
+                Remark::at(
+                    Span { start: 8, end: 11 },
+                    "removed loop that is never entered".to_string()
+                ),
+                Remark {
+                    span: None,
+                    message: "simplified 4 statements using known cell values".to_string()
+                },
+                Remark {
+                    span: None,
+                    message: "removed 3 dead stores".to_string()
+                }
+            ]
+        );
+    }
+}
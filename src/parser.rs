@@ -15,7 +15,9 @@
 //! ```
 //!
 //! ## Conversion:
-//! When converting to the intermediate representation we can use:
+//! Source is first parsed into a lossless [syntax tree](crate::syntax::Syntax)
+//! (keeping comments and whitespace), which is then converted to the
+//! intermediate representation using:
 //! ```
 //! <PtrLeft> => Stat::PtrMove(1)
 //! <PtrRight> => Stat::PtrMove(-1)
@@ -30,21 +32,84 @@
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_until},
-    character::complete::multispace1,
-    combinator::value,
+    character::complete::{multispace1, one_of},
+    combinator::map,
     multi::many0,
-    sequence::{delimited, tuple},
+    sequence::delimited,
     IResult,
 };
 
-use crate::intermediate::{BrainFuck, Op, Stat, Stats};
+use crate::{
+    intermediate::{BrainFuck, Span, Spans},
+    syntax::{Node, Nodes, Syntax},
+};
+
+/// An error in a source, with the span where it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl SyntaxError {
+    /// Locate the error in a source, given the rest of the source where
+    /// parsing stopped.
+    fn new(source: &str, rest: &str) -> Self {
+        let (rest, message) = locate(rest);
+        let start = source.len() - rest.len();
+        let len = if rest.starts_with("::") {
+            2
+        } else {
+            rest.chars().next().map_or(0, char::len_utf8)
+        };
+        SyntaxError {
+            span: Span {
+                start,
+                end: start + len,
+            },
+            message,
+        }
+    }
+}
+
+/// Find the rest of the source at the error, and describe it. Parsing stops
+/// before a loop containing an error, so the loop is searched for it.
+fn locate(rest: &str) -> (&str, String) {
+    let message = match rest.chars().next() {
+        Some('[') => match parse_nodes(&rest[1..]) {
+            Ok(("", _)) => "Unmatched opening bracket",
+            Ok((inner, _)) => return locate(inner),
+            Err(_) => "Syntax error",
+        },
+        Some(']') => "Unmatched closing bracket",
+        Some('#') => "Unterminated comment",
+        Some(':') if rest.starts_with("::") => "Unterminated insert",
+        Some(c) => return (rest, format!("Invalid character {:?}", c)),
+        None => "Syntax error",
+    };
+    (rest, message.to_string())
+}
 
 /// Parse a brainfuck program from a source string.
 pub fn parse(input: &str) -> Result<BrainFuck, &str> {
-    match parse_stats(input) {
-        Ok((rem, bf)) => {
+    parse_syntax(input).map(|syntax| BrainFuck::from(&syntax))
+}
+
+/// Parse a brainfuck program from a source string, with the spans of each
+/// statement in the source.
+pub fn parse_spanned(input: &str) -> Result<(BrainFuck, Spans), SyntaxError> {
+    parse_syntax(input)
+        .map(|syntax| syntax.lower())
+        .map_err(|rest| SyntaxError::new(input, rest))
+}
+
+/// Parse the concrete syntax tree of a source string, keeping comments and
+/// whitespace.
+pub fn parse_syntax(input: &str) -> Result<Syntax, &str> {
+    match parse_nodes(input) {
+        Ok((rem, nodes)) => {
             if rem.is_empty() {
-                Ok(BrainFuck(bf))
+                Ok(Syntax(nodes))
             } else {
                 Err(rem)
             }
@@ -54,48 +119,49 @@ pub fn parse(input: &str) -> Result<BrainFuck, &str> {
     }
 }
 
-/// Parse an assembly insert, placing the text inside into an assembly insert
-/// statement.
-fn get_insert(input: &str) -> IResult<&str, Stat> {
-    delimited(tag("::"), take_until("::"), tag("::"))(input)
-        .map(|(rem, asm)| (rem, Stat::Asm(asm.to_string())))
+/// Parse an assembly insert, placing the text inside into an insert node.
+fn get_insert(input: &str) -> IResult<&str, Node> {
+    map(
+        delimited(tag("::"), take_until("::"), tag("::")),
+        |asm: &str| Node::Insert(asm.to_string()),
+    )(input)
 }
 
-/// Get the inner instructions of a basic while loop
-fn get_while(input: &str) -> IResult<&str, Stat> {
-    delimited(tag("["), parse_stats, tag("]"))(input)
-        .map(|(rem, res)| (rem, Stat::WhileNonZero(res)))
+/// Get the inner nodes of a basic while loop
+fn get_while(input: &str) -> IResult<&str, Node> {
+    map(delimited(tag("["), parse_nodes, tag("]")), Node::Loop)(input)
 }
 
-/// Consume whitespace and comments.
-fn get_whitespace(input: &str) -> IResult<&str, Vec<()>> {
-    many0(alt((
-        value((), multispace1),
-        value((), tuple((tag("#"), take_until("#"), tag("#")))),
-    )))(input)
+/// Get a comment, keeping the text inside.
+fn get_comment(input: &str) -> IResult<&str, Node> {
+    map(
+        delimited(tag("#"), take_until("#"), tag("#")),
+        |comment: &str| Node::Comment(comment.to_string()),
+    )(input)
 }
 
-/// Parse statements into a vector of statements
-fn parse_stats(input: &str) -> IResult<&str, Stats> {
-    many0(delimited(
-        get_whitespace,
-        alt((
-            value(Stat::PtrMove(1), tag(">")),
-            value(Stat::PtrMove(-1), tag("<")),
-            value(Stat::DerefOp(Op::Add, 1), tag("+")),
-            value(Stat::DerefOp(Op::Add, -1), tag("-")),
-            value(Stat::Input, tag(",")),
-            value(Stat::Output, tag(".")),
-            get_insert,
-            get_while,
-        )),
+/// Get a run of whitespace.
+fn get_whitespace(input: &str) -> IResult<&str, Node> {
+    map(multispace1, |space: &str| {
+        Node::Whitespace(space.to_string())
+    })(input)
+}
+
+/// Parse source into a vector of syntax nodes
+fn parse_nodes(input: &str) -> IResult<&str, Nodes> {
+    many0(alt((
+        map(one_of("<>+-,."), Node::Command),
         get_whitespace,
-    ))(input)
+        get_comment,
+        get_insert,
+        get_while,
+    )))(input)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::intermediate::{Op, Span, SpanTree, Stat};
 
     #[test]
     fn parses_assembly_inserts() {
@@ -268,4 +334,75 @@ mod tests {
             ]))
         );
     }
+
+    #[test]
+    fn syntax_is_lossless() {
+        for source in [
+            "",
+            " \t\n [[<]::ldr r3, =345::++]++",
+            "#++++#--::::#<<-->>#\n++",
+            "::::\t# hello # \n # world #+# hello #+  # hello #",
+        ] {
+            assert_eq!(parse_syntax(source).unwrap().to_string(), source);
+        }
+    }
+
+    #[test]
+    fn spans_statements() {
+        let source = "#inc# +\n[::nop::-]";
+        let (_, spans) = parse_spanned(source).unwrap();
+        assert_eq!(
+            spans,
+            vec![
+                SpanTree(Span { start: 6, end: 7 }, vec![]),
+                SpanTree(
+                    Span { start: 8, end: 18 },
+                    vec![
+                        SpanTree(Span { start: 9, end: 16 }, vec![]),
+                        SpanTree(Span { start: 16, end: 17 }, vec![])
+                    ]
+                )
+            ]
+        );
+        assert_eq!(spans[1].0.location(source), (2, 1));
+        assert_eq!(spans[1].1[1].0.location(source), (2, 9));
+    }
+
+    #[test]
+    fn syntax_keeps_comments() {
+        assert_eq!(
+            parse_syntax("+ #inc#[-]"),
+            Ok(Syntax(vec![
+                Node::Command('+'),
+                Node::Whitespace(" ".to_string()),
+                Node::Comment("inc".to_string()),
+                Node::Loop(vec![Node::Command('-')])
+            ]))
+        );
+    }
+
+    #[test]
+    fn locates_syntax_errors() {
+        let error = |source| {
+            let SyntaxError { span, message } = parse_spanned(source).unwrap_err();
+            (&source[span.start..span.end], span.start, message)
+        };
+        assert_eq!(
+            error("+[->+<]] "),
+            ("]", 7, "Unmatched closing bracket".to_string())
+        );
+        assert_eq!(
+            error("+[[->+<]"),
+            ("[", 1, "Unmatched opening bracket".to_string())
+        );
+        assert_eq!(
+            error("+[-# x]"),
+            ("#", 3, "Unterminated comment".to_string())
+        );
+        assert_eq!(
+            error("[+[->+<] é]"),
+            ("é", 9, "Invalid character 'é'".to_string())
+        );
+        assert_eq!(error("::mov"), ("::", 0, "Unterminated insert".to_string()));
+    }
 }
@@ -0,0 +1,213 @@
+//! An interactive session running lines of brainfuck on a persistent tape.
+//!
+//! Each line is parsed and run (unoptimised) with the interpreter, keeping the
+//! tape and pointer between lines, then the cells around the pointer are
+//! shown. Input for `,` is read from the same input as the lines. Lines
+//! starting with `:` (but not `::`, which starts an insert) are commands.
+
+use std::io::{BufRead, Write};
+
+use crate::{
+    intermediate::{BrainFuck, Spans},
+    interpreter::Machine,
+    optimiser::optimise_part,
+    parser::{parse_spanned, SyntaxError},
+};
+
+/// The number of cells shown either side of the pointer.
+const NEARBY: i32 = 4;
+
+/// The number of cells on each line of a dump of the tape.
+const ROW: usize = 16;
+
+const HELP: &str = "\
+Enter brainfuck to run it on the tape, or a command:
+  :reset  clear the tape and return the pointer to the start
+  :tape   show every cell up to the last non-zero cell (or the pointer)
+  :ir     show the optimised IR of the last line
+  :help   show this message
+  :quit   leave the repl (as does the end of input)";
+
+/// A writer remembering whether the last byte written ended a line.
+struct Output<'a, W: Write> {
+    inner: &'a mut W,
+    line_start: bool,
+}
+
+impl<W: Write> Write for Output<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(last) = buf[..written].last() {
+            self.line_start = *last == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The cells around the pointer, with their offsets from the initial pointer
+/// position (and the pointer's cell in brackets).
+fn nearby(machine: &Machine) -> String {
+    let (tape, start) = machine.tape();
+    let ptr = machine.ptr();
+    let offsets: Vec<i32> = (ptr - NEARBY..=ptr + NEARBY)
+        .filter(|offset| (0..tape.len() as i32).contains(&(start as i32 + offset)))
+        .collect();
+    let (mut header, mut values) = (String::new(), String::new());
+    for offset in offsets {
+        let value = tape[(start as i32 + offset) as usize];
+        header.push_str(&format!("{:>6} ", offset));
+        values.push_str(&if offset == ptr {
+            format!("{:>7}", format!("[{}]", value))
+        } else {
+            format!("{:>6} ", value)
+        });
+    }
+    format!(
+        "pointer at {}\n{}\n{}",
+        ptr,
+        header.trim_end(),
+        values.trim_end()
+    )
+}
+
+/// Every cell up to the last non-zero cell (or the pointer), in rows labelled
+/// with the offset of their first cell.
+fn dump(machine: &Machine) -> String {
+    let (tape, start) = machine.tape();
+    let ptr = (start as i32 + machine.ptr()) as usize;
+    let end = tape
+        .iter()
+        .rposition(|cell| *cell != 0)
+        .map_or(ptr, |last| last.max(ptr))
+        + 1;
+    let first = tape[..start.min(ptr)]
+        .iter()
+        .position(|cell| *cell != 0)
+        .unwrap_or(start.min(ptr));
+    tape[first..end]
+        .chunks(ROW)
+        .enumerate()
+        .map(|(row, cells)| {
+            let offset = (first + row * ROW) as i64 - start as i64;
+            let cells: Vec<String> = cells.iter().map(|cell| format!("{:>3}", cell)).collect();
+            format!("{:>7}: {}", offset, cells.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Run a session, reading lines from an input and writing to an output, with
+/// pre cells before the initial pointer position and post cells after.
+pub fn repl(input: &mut impl BufRead, output: &mut impl Write, pre: u32, post: u32) {
+    let mut machine = Machine::new(pre, post);
+    let mut last: Option<(BrainFuck, Spans)> = None;
+    // the session ends if the output is closed
+    let _ = writeln!(output, "brainfuck repl, enter :help for commands");
+    loop {
+        let _ = write!(output, "bf> ");
+        let _ = output.flush();
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                let _ = writeln!(output);
+                return;
+            }
+            Ok(_) => (),
+        }
+        let line = line.trim_end_matches(['\n', '\r']);
+
+        let reply = match line.trim() {
+            "" => continue,
+            ":quit" | ":q" => return,
+            ":help" => HELP.to_string(),
+            ":reset" => {
+                machine = Machine::new(pre, post);
+                nearby(&machine)
+            }
+            ":tape" => dump(&machine),
+            ":ir" => match &last {
+                Some((ir, spans)) => {
+                    let (ir, _) = optimise_part(ir.clone(), spans.clone(), &[]);
+                    match format!("{}", ir).trim_end() {
+                        "" => "(no statements, as the line has no effect)".to_string(),
+                        ir => ir.to_string(),
+                    }
+                }
+                None => "No line has been run".to_string(),
+            },
+            command if command.starts_with(':') && !command.starts_with("::") => {
+                format!("Unknown command {}, enter :help for commands", command)
+            }
+            _ => match parse_spanned(line) {
+                Ok((ir, spans)) => {
+                    let mut program_output = Output {
+                        inner: output,
+                        line_start: true,
+                    };
+                    let result = machine.run(&ir.0, input, &mut program_output);
+                    let line_start = program_output.line_start;
+                    if !line_start {
+                        let _ = writeln!(output);
+                    }
+                    last = Some((ir, spans));
+                    match result {
+                        Ok(()) => nearby(&machine),
+                        Err(err) => format!("{}\n{}", err, nearby(&machine)),
+                    }
+                }
+                Err(SyntaxError { message, .. }) => message,
+            },
+        };
+        let _ = writeln!(output, "{}", reply);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(input: &str) -> String {
+        let mut output = Vec::new();
+        repl(&mut input.as_bytes(), &mut output, 2, 30);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_tape_between_lines() {
+        let output = session("+++>++\n<[->+<]>.\n");
+        assert!(output.ends_with(
+            "bf> pointer at 1\n\
+             \x20   -2     -1      0      1      2      3      4      5\n\
+             \x20    0      0      3     [2]     0      0      0      0\n\
+             bf> \x05\n\
+             pointer at 1\n\
+             \x20   -2     -1      0      1      2      3      4      5\n\
+             \x20    0      0      0     [5]     0      0      0      0\n\
+             bf> \n"
+        ));
+    }
+
+    #[test]
+    fn runs_commands() {
+        let output =
+            session(":ir\n>>+<<<-\n:tape\n:reset\n:tape\n:ir\n+.\n:ir\n[-]+.\n:ir\n:nope\n[\n");
+        assert!(output.contains("bf> No line has been run\n"));
+        assert!(output.contains("bf>      -1: 255   0   0   1\nbf> "));
+        assert!(output.contains("bf>       0:   0\nbf> >>+<<<-\n"));
+        // the tape is kept between lines, so only values set in the line are
+        // known
+        assert!(output.contains("bf> +.\nbf> "));
+        assert!(output.contains("bf> =(0){1}\"\\x01\"\nbf> Unknown command :nope"));
+        assert!(output.ends_with("bf> Unmatched opening bracket\nbf> \n"));
+    }
+
+    #[test]
+    fn reports_errors_keeping_the_tape() {
+        let output = session("+<<<\n");
+        assert!(output.contains("The pointer moved outside of the tape\npointer at -2\n"));
+    }
+}
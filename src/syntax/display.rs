@@ -0,0 +1,31 @@
+//! Display for the concrete syntax tree, reproducing the original source.
+
+use super::{Node, Syntax};
+use std::fmt::Display;
+
+impl Display for Syntax {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for node in &self.0 {
+            write!(f, "{}", node)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Node::Command(c) => write!(f, "{}", c),
+            Node::Loop(nodes) => {
+                write!(f, "[")?;
+                for node in nodes {
+                    write!(f, "{}", node)?;
+                }
+                write!(f, "]")
+            }
+            Node::Insert(asm) => write!(f, "::{}::", asm),
+            Node::Comment(comment) => write!(f, "#{}#", comment),
+            Node::Whitespace(space) => write!(f, "{}", space),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! A lossless concrete syntax tree of brainfuck source. Unlike the
+//! intermediate representation, it keeps whitespace, comments and inserts
+//! exactly as written, so tools like the formatter can reproduce them.
+
+pub mod display;
+
+use crate::intermediate::{BrainFuck, Op, Span, SpanTree, Spans, Stat, Stats};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Syntax(pub Nodes);
+
+/// The source consists of syntax nodes.
+pub type Nodes = Vec<Node>;
+
+/// Basic syntax node types
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// One of the basic brainfuck commands `<>+-,.`
+    Command(char),
+    Loop(Nodes),
+    Insert(String),
+    Comment(String),
+    Whitespace(String),
+}
+
+impl From<&Syntax> for BrainFuck {
+    fn from(syntax: &Syntax) -> Self {
+        syntax.lower().0
+    }
+}
+
+impl Syntax {
+    /// Convert to the intermediate representation, keeping the source spans
+    /// of each statement.
+    pub fn lower(&self) -> (BrainFuck, Spans) {
+        let (stats, spans, _) = lower_nodes(&self.0, 0);
+        (BrainFuck(stats), spans)
+    }
+}
+
+/// Convert syntax nodes starting at byte offset start to intermediate
+/// representation statements, dropping whitespace and comments. Returns the
+/// statements, their spans and the offset of the end of the nodes.
+fn lower_nodes(nodes: &[Node], start: usize) -> (Stats, Spans, usize) {
+    let mut stats = Vec::new();
+    let mut spans = Vec::new();
+    let mut offset = start;
+    for node in nodes {
+        let (stat, inner, end) = match node {
+            Node::Command('>') => (Some(Stat::PtrMove(1)), vec![], offset + 1),
+            Node::Command('<') => (Some(Stat::PtrMove(-1)), vec![], offset + 1),
+            Node::Command('+') => (Some(Stat::DerefOp(Op::Add, 1)), vec![], offset + 1),
+            Node::Command('-') => (Some(Stat::DerefOp(Op::Add, -1)), vec![], offset + 1),
+            Node::Command(',') => (Some(Stat::Input), vec![], offset + 1),
+            Node::Command('.') => (Some(Stat::Output), vec![], offset + 1),
+            Node::Command(c) => panic!("'{}' is not a brainfuck command", c),
+            Node::Loop(nodes) => {
+                let (body, inner, end) = lower_nodes(nodes, offset + 1);
+                (Some(Stat::WhileNonZero(body)), inner, end + 1)
+            }
+            Node::Insert(asm) => (Some(Stat::Asm(asm.clone())), vec![], offset + asm.len() + 4),
+            Node::Comment(comment) => (None, vec![], offset + comment.len() + 2),
+            Node::Whitespace(space) => (None, vec![], offset + space.len()),
+        };
+        if let Some(stat) = stat {
+            stats.push(stat);
+            spans.push(SpanTree(Span { start: offset, end }, inner));
+        }
+        offset = end;
+    }
+    (stats, spans, offset)
+}
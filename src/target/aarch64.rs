@@ -0,0 +1,450 @@
+//! Compilation to AArch64 (A64) assembly for Linux, in GAS syntax.
+//!
+//! `x19` holds the pointer, and the tape is placed in `.bss`. Pointer moves
+//! are not applied immediately, instead the pending offset is used in the
+//! addressing of loads and stores (e.g `>>+<` only accesses
+//! `[x19, #2]`), and is only added to `x19` when required (before loops and
+//! inserts, or when out of range of the offset addressing).
+//!
+//! Output is buffered (unless disabled), and flushed on newline, before input
+//! and at exit. Scans for a zero cell (`[>]` and `[<]`) check 16 cells at a
+//! time with Advanced SIMD, so the tape is padded to keep these reads in
+//! bounds. Freestanding programs scan a cell at a time, as SIMD may not be
+//! enabled.
+//!
+//! To assemble, link and run:
+//! ```text
+//! aarch64-linux-gnu-as prog.s -o prog.o && aarch64-linux-gnu-ld prog.o -o prog && ./prog
+//! ```
+//!
+//! Freestanding programs set the stack from `__stack_top`, and halt with `wfi`
+//! once finished. With I/O hooks provided in `io.s`, and linked at the start of
+//! RAM (`--load-address 0x40000000`):
+//! ```text
+//! aarch64-linux-gnu-as prog.s io.s -o prog.o && aarch64-linux-gnu-ld -T prog.ld prog.o -o prog
+//! qemu-system-aarch64 -machine virt -cpu cortex-a53 -nographic -kernel prog
+//! ```
+
+use std::fmt::{Formatter, FormattingOptions};
+
+use super::{Freestanding, Hook, Settings};
+use crate::intermediate::{BrainFuck, Op, Stat};
+
+/// Output buffering routines: `.Lput` adds the byte in `w0` to the buffer,
+/// flushing on a newline or when full, and `.Lflush` writes the buffer.
+const BUFFER: &str = ".Lput:
+	adrp x9, outlen
+	add x9, x9, :lo12:outlen
+	ldr x10, [x9]
+	adrp x11, outbuf
+	add x11, x11, :lo12:outbuf
+	strb w0, [x11, x10]
+	add x10, x10, #1
+	str x10, [x9]
+	cmp w0, #10
+	b.eq .Lflush
+	cmp x10, #4096
+	b.eq .Lflush
+	ret
+.Lflush:
+	adrp x9, outlen
+	add x9, x9, :lo12:outlen
+	ldr x2, [x9]
+	str xzr, [x9]
+	mov x8, #64
+	mov x0, #1
+	adrp x1, outbuf
+	add x1, x1, :lo12:outbuf
+	svc #0
+	ret
+
+	.section .bss
+	.align 3
+outlen:
+	.zero 8
+outbuf:
+	.zero 4096";
+
+/// Cells either side of the tape read by scans.
+const SCAN_PADDING: u32 = 16;
+
+/// Compile the brainfuck program, given pre bytes of memory before the
+/// starting position, and post bytes after.
+pub fn compile(BrainFuck(stats): &BrainFuck, settings: &Settings) -> String {
+    let Settings {
+        pre,
+        post,
+        freestanding,
+        buffered,
+        ..
+    } = settings;
+    let mut result = String::new();
+    let mut f = Formatter::new(&mut result, FormattingOptions::new());
+
+    match freestanding {
+        None => writeln!(f, "\t.section .text\n\t.globl _start\n_start:"),
+        Some(_) => writeln!(
+            f,
+            "\t.section .text._start\n\t.globl _start\n_start:\n\tldr x0, =__stack_top\n\tmov sp, x0"
+        ),
+    }
+    .expect("Writing to string");
+
+    match freestanding.as_ref().and_then(|freestanding| freestanding.tape_address) {
+        None => writeln!(
+            f,
+            "\tadrp x19, cells\n\tadd x19, x19, :lo12:cells\n\n\t.section .bss\n\t.zero {}\ncells:\n\t.zero {}\n\t.section .text",
+            SCAN_PADDING,
+            pre + post + SCAN_PADDING
+        ),
+        Some(address) => writeln!(
+            f,
+            "\tldr x19, ={:#x}\n\tldr x9, ={}\n\tmov x10, #0\n.Lzero:\n\tcmp x10, x9\n\tb.hs .Lzeroend\n\tstrb wzr, [x19, x10]\n\tadd x10, x10, #1\n\tb .Lzero\n.Lzeroend:",
+            address,
+            pre + post
+        ),
+    }
+    .expect("Writing to string");
+
+    // the initial pointer position is just a pending offset
+    let mut pointer = Pointer {
+        offset: *pre as i32,
+        labels: 0,
+        freestanding: freestanding.as_ref(),
+        // freestanding programs use the hooks for output, so are not buffered
+        buffered: *buffered && freestanding.is_none(),
+    };
+    for stat in stats {
+        pointer
+            .transpile_stat(stat, &mut f)
+            .expect("Writing to string");
+    }
+
+    match freestanding {
+        None if pointer.buffered => writeln!(
+            f,
+            "\tbl .Lflush\n\tmov x8, #93\n\tmov x0, #0\n\tsvc #0\n\n{}",
+            BUFFER
+        ),
+        None => writeln!(f, "\tmov x8, #93\n\tmov x0, #0\n\tsvc #0"),
+        Some(_) => writeln!(f, ".Lhalt:\n\twfi\n\tb .Lhalt"),
+    }
+    .expect("Writing to string");
+
+    result
+}
+
+/// Write bytes to `.rodata` at a label, and load its address into a register.
+fn rodata(label: usize, bytes: &[u8], reg: &str, f: &mut Formatter<'_>) -> std::fmt::Result {
+    writeln!(
+        f,
+        "\t.pushsection .rodata\n.Lstr{}:\n\t.byte {}\n\t.popsection\n\tadrp {}, .Lstr{}\n\tadd {}, {}, :lo12:.Lstr{}",
+        label,
+        bytes
+            .iter()
+            .map(|byte| byte.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        reg,
+        label,
+        reg,
+        reg,
+        label
+    )
+}
+
+/// Write inserted assembly (an insert or I/O template), one instruction per
+/// line.
+fn insert(asm: &str, f: &mut Formatter<'_>) -> std::fmt::Result {
+    for line in asm.lines() {
+        writeln!(f, "\t{}", line.trim())?;
+    }
+    Ok(())
+}
+
+/// The offset of the pointer from x19, the number of loops written, and how
+/// I/O is done.
+struct Pointer<'a> {
+    offset: i32,
+    labels: usize,
+    freestanding: Option<&'a Freestanding>,
+    buffered: bool,
+}
+
+impl Pointer<'_> {
+    /// Apply the pending offset to x19.
+    fn flush(&mut self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let offset = self.offset;
+        self.offset = 0;
+        match offset {
+            0 => Ok(()),
+            1..=4095 => writeln!(f, "\tadd x19, x19, #{}", offset),
+            -4095..=-1 => writeln!(f, "\tsub x19, x19, #{}", -offset),
+            _ => writeln!(f, "\tldr x9, ={}\n\tadd x19, x19, x9", offset),
+        }
+    }
+
+    /// Load (ld) or store (st) a byte of the current cell to/from a register.
+    fn access(&mut self, op: &str, reg: &str, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.offset {
+            0..=4095 => writeln!(f, "\t{}rb {}, [x19, #{}]", op, reg, self.offset),
+            -256..=-1 => writeln!(f, "\t{}urb {}, [x19, #{}]", op, reg, self.offset),
+            _ => {
+                self.flush(f)?;
+                writeln!(f, "\t{}rb {}, [x19]", op, reg)
+            }
+        }
+    }
+
+    /// Write a syscall on the current cell (read or write, with one byte).
+    fn syscall(&mut self, number: u8, fd: u8, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !(-4095..=4095).contains(&self.offset) {
+            self.flush(f)?;
+        }
+        writeln!(f, "\tmov x8, #{}\n\tmov x0, #{}", number, fd)?;
+        if self.offset < 0 {
+            writeln!(f, "\tsub x1, x19, #{}", -self.offset)?;
+        } else {
+            writeln!(f, "\tadd x1, x19, #{}", self.offset)?;
+        }
+        writeln!(f, "\tmov x2, #1\n\tsvc #0")
+    }
+
+    fn transpile_stat(&mut self, stat: &Stat, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match stat {
+            Stat::PtrMove(i) => {
+                self.offset += i;
+                Ok(())
+            }
+            Stat::DerefOp(op, i) => {
+                let i = i.rem_euclid(256);
+                self.access("ld", "w0", f)?;
+                match op {
+                    Op::Add => writeln!(f, "\tadd w0, w0, #{}", i)?,
+                    Op::Mul => writeln!(f, "\tmov w1, #{}\n\tmul w0, w0, w1", i)?,
+                    Op::Div | Op::Mod if i == 0 => writeln!(f, "\tmov w0, #0")?,
+                    Op::Div => writeln!(f, "\tmov w1, #{}\n\tudiv w0, w0, w1", i)?,
+                    Op::Mod => writeln!(
+                        f,
+                        "\tmov w1, #{}\n\tudiv w2, w0, w1\n\tmsub w0, w2, w1, w0",
+                        i
+                    )?,
+                }
+                self.access("st", "w0", f)
+            }
+            Stat::MulAdd(offset, factor) => {
+                self.access("ld", "w0", f)?;
+                let label = self.labels;
+                self.labels += 1;
+                writeln!(f, "\tcbz w0, .Lskip{}", label)?;
+                // skipped if the current cell is zero, so the pending offset
+                // cannot be flushed, and a far target is addressed with x9
+                let target = self.offset + offset;
+                let address = match target {
+                    -256..=4095 => format!("[x19, #{}]", target),
+                    _ => {
+                        writeln!(f, "\tldr x9, ={}\n\tadd x9, x19, x9", target)?;
+                        "[x9]".to_string()
+                    }
+                };
+                let (load, store) = if target < 0 {
+                    ("ldurb", "sturb")
+                } else {
+                    ("ldrb", "strb")
+                };
+                writeln!(
+                    f,
+                    "\t{} w1, {}\n\tmov w2, #{}\n\tmadd w1, w0, w2, w1\n\t{} w1, {}\n.Lskip{}:",
+                    load,
+                    address,
+                    factor.rem_euclid(256),
+                    store,
+                    address,
+                    label
+                )
+            }
+            Stat::Output => match self.freestanding.map(|freestanding| &freestanding.output) {
+                None if self.buffered => {
+                    self.access("ld", "w0", f)?;
+                    writeln!(f, "\tbl .Lput")
+                }
+                None => self.syscall(64, 1, f),
+                Some(Hook::Extern(name)) => {
+                    // x19 is callee saved, so the offset can stay pending
+                    self.access("ld", "w0", f)?;
+                    writeln!(f, "\tbl {}", name)
+                }
+                Some(Hook::Template(asm)) => {
+                    self.flush(f)?;
+                    insert(asm, f)
+                }
+            },
+            Stat::OutputConst(bytes) => {
+                match self.freestanding.map(|freestanding| &freestanding.output) {
+                    None => {
+                        if self.buffered {
+                            writeln!(f, "\tbl .Lflush")?;
+                        }
+                        let label = self.labels;
+                        self.labels += 1;
+                        writeln!(f, "\tmov x8, #64\n\tmov x0, #1")?;
+                        rodata(label, bytes, "x1", f)?;
+                        writeln!(f, "\tldr x2, ={}\n\tsvc #0", bytes.len())
+                    }
+                    Some(Hook::Extern(name)) => {
+                        for byte in bytes {
+                            writeln!(f, "\tmov w0, #{}\n\tbl {}", byte, name)?;
+                        }
+                        Ok(())
+                    }
+                    Some(Hook::Template(asm)) => {
+                        // point x19 at each byte in turn, as the template
+                        // outputs the current cell
+                        self.flush(f)?;
+                        let label = self.labels;
+                        self.labels += 1;
+                        writeln!(f, "\tstr x19, [sp, #-16]!")?;
+                        rodata(label, bytes, "x19", f)?;
+                        for _ in bytes {
+                            insert(asm, f)?;
+                            writeln!(f, "\tadd x19, x19, #1")?;
+                        }
+                        writeln!(f, "\tldr x19, [sp], #16")
+                    }
+                }
+            }
+            Stat::Input => match self.freestanding.map(|freestanding| &freestanding.input) {
+                None => {
+                    if self.buffered {
+                        writeln!(f, "\tbl .Lflush")?;
+                    }
+                    writeln!(f, "\tmov w0, #255")?;
+                    self.access("st", "w0", f)?;
+                    self.syscall(63, 0, f)
+                }
+                Some(Hook::Extern(name)) => {
+                    writeln!(f, "\tbl {}", name)?;
+                    self.access("st", "w0", f)
+                }
+                Some(Hook::Template(asm)) => {
+                    self.flush(f)?;
+                    insert(asm, f)
+                }
+            },
+            Stat::ScanZero(stride) => {
+                self.flush(f)?;
+                let label = self.labels;
+                self.labels += 1;
+                match stride {
+                    // each byte of the comparison is narrowed to a nibble of
+                    // x9, so the first (or last) set nibble is the zero cell
+                    1 if self.freestanding.is_none() => writeln!(
+                        f,
+                        ".Lscan{l}:\n\tld1 {{v0.16b}}, [x19]\n\tcmeq v0.16b, v0.16b, #0\n\tshrn v0.8b, v0.8h, #4\n\tfmov x9, d0\n\tcbnz x9, .Lscanend{l}\n\tadd x19, x19, #16\n\tb .Lscan{l}\n.Lscanend{l}:\n\trbit x9, x9\n\tclz x9, x9\n\tadd x19, x19, x9, lsr #2",
+                        l = label
+                    ),
+                    -1 if self.freestanding.is_none() => writeln!(
+                        f,
+                        ".Lscan{l}:\n\tsub x10, x19, #15\n\tld1 {{v0.16b}}, [x10]\n\tcmeq v0.16b, v0.16b, #0\n\tshrn v0.8b, v0.8h, #4\n\tfmov x9, d0\n\tcbnz x9, .Lscanend{l}\n\tsub x19, x19, #16\n\tb .Lscan{l}\n.Lscanend{l}:\n\tclz x9, x9\n\tmov x11, #63\n\tsub x9, x11, x9\n\tadd x19, x10, x9, lsr #2",
+                        l = label
+                    ),
+                    _ => {
+                        writeln!(f, ".Lscan{}:\n\tldrb w0, [x19]\n\tcbz w0, .Lscanend{}", label, label)?;
+                        self.offset = *stride;
+                        self.flush(f)?;
+                        writeln!(f, "\tb .Lscan{}\n.Lscanend{}:", label, label)
+                    }
+                }
+            }
+            Stat::WhileNonZero(stats) => {
+                let label = self.labels;
+                self.labels += 1;
+                self.flush(f)?;
+                writeln!(
+                    f,
+                    ".Lloop{}:\n\tldrb w0, [x19]\n\tcbz w0, .Lend{}",
+                    label, label
+                )?;
+                for stat in stats {
+                    self.transpile_stat(stat, f)?;
+                }
+                self.flush(f)?;
+                writeln!(f, "\tb .Lloop{}\n.Lend{}:", label, label)
+            }
+            Stat::SetCells(offset, cells) => {
+                self.offset += offset;
+                for (i, cell) in cells.iter().enumerate() {
+                    writeln!(f, "\tmov w0, #{}", cell)?;
+                    self.access("st", "w0", f)?;
+                    if i + 1 < cells.len() {
+                        self.offset += 1;
+                    }
+                }
+                self.offset -= offset + cells.len().saturating_sub(1) as i32;
+                Ok(())
+            }
+            Stat::Asm(asm) => {
+                self.flush(f)?;
+                writeln!(f, "\t// Start of inserted section")?;
+                insert(asm, f)?;
+                writeln!(f, "\t// End of inserted section")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn uses_offset_addressing() {
+        let BrainFuck(stats) = parse(">>+<<<-.").unwrap();
+        let mut result = String::new();
+        let mut f = Formatter::new(&mut result, FormattingOptions::new());
+        let mut pointer = Pointer {
+            offset: 0,
+            labels: 0,
+            freestanding: None,
+            buffered: false,
+        };
+        for stat in &stats {
+            pointer.transpile_stat(stat, &mut f).unwrap();
+        }
+        assert_eq!(
+            result,
+            "\tldrb w0, [x19, #2]\n\tadd w0, w0, #1\n\tstrb w0, [x19, #2]\n\
+             \tldurb w0, [x19, #-1]\n\tadd w0, w0, #255\n\tsturb w0, [x19, #-1]\n\
+             \tmov x8, #64\n\tmov x0, #1\n\tsub x1, x19, #1\n\tmov x2, #1\n\tsvc #0\n"
+        );
+        assert_eq!(pointer.offset, -1);
+    }
+
+    #[test]
+    fn calls_io_hooks() {
+        let freestanding = Freestanding {
+            output: Hook::Extern("put".to_string()),
+            input: Hook::Template("bl get\nstrb w0, [x19]".to_string()),
+            tape_address: None,
+            load_address: 0x40000000,
+        };
+        let BrainFuck(stats) = parse(">.>,").unwrap();
+        let mut result = String::new();
+        let mut f = Formatter::new(&mut result, FormattingOptions::new());
+        let mut pointer = Pointer {
+            offset: 0,
+            labels: 0,
+            freestanding: Some(&freestanding),
+            buffered: false,
+        };
+        for stat in &stats {
+            pointer.transpile_stat(stat, &mut f).unwrap();
+        }
+        assert_eq!(
+            result,
+            "\tldrb w0, [x19, #1]\n\tbl put\n\
+             \tadd x19, x19, #2\n\tbl get\n\tstrb w0, [x19]\n"
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! Reverse translation back to plain, portable brainfuck.
+//!
+//! Extended operations are expanded into runs of the basic commands, so the
+//! result can be run on any third party interpreter (and doubles as a
+//! minifier). Assembly inserts cannot be expressed, and are stubbed out with a
+//! comment in their place.
+
+use std::{
+    collections::HashMap,
+    fmt::{Formatter, FormattingOptions},
+};
+
+use super::Settings;
+use crate::intermediate::{BrainFuck, Op, Stat};
+
+/// Compile the brainfuck program to portable brainfuck. As the tape size and
+/// I/O are implementation defined for brainfuck, the settings are not used.
+pub fn compile(BrainFuck(stats): &BrainFuck, _settings: &Settings) -> String {
+    let mut result = String::new();
+    let mut f = Formatter::new(&mut result, FormattingOptions::new());
+
+    transpile_stats(stats, &mut Known::new(true), &mut f).expect("Writing to string");
+
+    writeln!(f).expect("Writing to string");
+
+    result
+}
+
+/// The cells of known value while writing statements, by offset from the
+/// pointer at the start of the statements, so constant output can be written
+/// using the current cell.
+struct Known {
+    offset: i32,
+    cells: HashMap<i32, Option<u8>>,
+    /// If the cells not present in cells are zero.
+    zeroed: bool,
+}
+
+impl Known {
+    fn new(zeroed: bool) -> Self {
+        Known {
+            offset: 0,
+            cells: HashMap::new(),
+            zeroed,
+        }
+    }
+
+    fn get(&self, offset: i32) -> Option<u8> {
+        match self.cells.get(&(self.offset + offset)) {
+            Some(value) => *value,
+            None if self.zeroed => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, offset: i32, value: Option<u8>) {
+        self.cells.insert(self.offset + offset, value);
+    }
+
+    /// Update the known cells after a statement.
+    fn update(&mut self, stat: &Stat) {
+        match stat {
+            Stat::PtrMove(i) => self.offset += i,
+            Stat::DerefOp(Op::Add, i) => {
+                let value = self.get(0).map(|value| value.wrapping_add(*i as u8));
+                self.set(0, value)
+            }
+            Stat::DerefOp(_, _) | Stat::Input => self.set(0, None),
+            Stat::MulAdd(target, _) => self.set(*target, None),
+            Stat::SetCells(start, cells) => {
+                for (i, cell) in cells.iter().enumerate() {
+                    self.set(start + i as i32, Some(*cell))
+                }
+            }
+            // loops may move the pointer, so start again from the zero cell
+            // they end on
+            Stat::WhileNonZero(_) | Stat::ScanZero(_) => {
+                *self = Known::new(false);
+                self.set(0, Some(0))
+            }
+            Stat::Asm(_) => *self = Known::new(false),
+            Stat::Output | Stat::OutputConst(_) => (),
+        }
+    }
+}
+
+/// Write a symbol n times.
+fn repeat(symbol: char, n: u32, f: &mut Formatter<'_>) -> std::fmt::Result {
+    for _ in 0..n {
+        write!(f, "{}", symbol)?;
+    }
+    Ok(())
+}
+
+/// Add to the current cell, using whichever of '+' and '-' is shortest given
+/// cells wrap at 256.
+fn add(i: i32, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match i.rem_euclid(256) as u32 {
+        inc @ 0..=128 => repeat('+', inc, f),
+        inc => repeat('-', 256 - inc, f),
+    }
+}
+
+/// Check if a statement sets cells other than the current cell and the
+/// targets of some multiply-adds (so can be moved before them).
+fn sets_other_cells(stat: &Stat, targets: &[(i32, i32)]) -> bool {
+    matches!(stat, Stat::SetCells(start, cells)
+        if (*start..*start + cells.len() as i32)
+            .all(|cell| cell != 0 && targets.iter().all(|(target, _)| *target != cell)))
+}
+
+fn transpile_stats(
+    mut stats: &[Stat],
+    known: &mut Known,
+    f: &mut Formatter<'_>,
+) -> std::fmt::Result {
+    while let Some(stat) = stats.first() {
+        if !matches!(stat, Stat::MulAdd(_, _)) {
+            transpile_stat(stat, known, f)?;
+            known.update(stat);
+            stats = &stats[1..];
+            continue;
+        }
+
+        // multiply-adds are only produced from loops, followed by a clear of
+        // the current cell (unless it is dead), so the run is expanded back into
+        // a loop consuming the current cell
+        let mut targets = Vec::new();
+        let mut sets = Vec::new();
+        while let Some(stat) = stats.first() {
+            match stat {
+                Stat::MulAdd(offset, factor) => targets.push((*offset, *factor)),
+                stat if sets_other_cells(stat, &targets) => sets.push(stat),
+                _ => break,
+            }
+            stats = &stats[1..];
+        }
+        for stat in sets {
+            transpile_stat(stat, known, f)?;
+            known.update(stat);
+        }
+        write!(f, "[-")?;
+        let mut current = 0;
+        for (offset, factor) in targets {
+            transpile_stat(&Stat::PtrMove(offset - current), known, f)?;
+            add(factor, f)?;
+            known.set(offset, None);
+            current = offset;
+        }
+        transpile_stat(&Stat::PtrMove(-current), known, f)?;
+        write!(f, "]")?;
+        known.set(0, Some(0));
+        if let Some(Stat::SetCells(0, cells)) = stats.first() {
+            if cells[..] == [0] {
+                stats = &stats[1..];
+            }
+        }
+    }
+    Ok(())
+}
+
+fn transpile_stat(stat: &Stat, known: &Known, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match stat {
+        Stat::PtrMove(i) if *i < 0 => repeat('<', i.unsigned_abs(), f),
+        Stat::PtrMove(i) => repeat('>', *i as u32, f),
+        Stat::DerefOp(Op::Add, i) => add(*i, f),
+        Stat::DerefOp(Op::Mul, 1) | Stat::DerefOp(Op::Div, 1) => Ok(()),
+        Stat::DerefOp(Op::Mul, 0) | Stat::DerefOp(Op::Mod, 1) => write!(f, "[-]"),
+        Stat::DerefOp(op, i) => panic!(
+            "{}({}) cannot be expressed in brainfuck without a scratch cell",
+            op, i
+        ),
+        Stat::Output => write!(f, "."),
+        Stat::OutputConst(bytes) => {
+            // the current cell is changed to each byte, then restored
+            let start = i32::from(known.get(0).unwrap_or_else(|| {
+                panic!(
+                    "constant output cannot be expressed in brainfuck at a cell of unknown value"
+                )
+            }));
+            let mut current = start;
+            for byte in bytes {
+                add(i32::from(*byte) - current, f)?;
+                write!(f, ".")?;
+                current = i32::from(*byte);
+            }
+            add(start - current, f)
+        }
+        Stat::Input => write!(f, ","),
+        Stat::ScanZero(stride) => {
+            write!(f, "[")?;
+            transpile_stat(&Stat::PtrMove(*stride), known, f)?;
+            write!(f, "]")
+        }
+        Stat::WhileNonZero(stats) => {
+            write!(f, "[")?;
+            transpile_stats(stats, &mut Known::new(false), f)?;
+            write!(f, "]")
+        }
+        Stat::MulAdd(_, _) => {
+            transpile_stats(std::slice::from_ref(stat), &mut Known::new(false), f)
+        }
+        Stat::SetCells(offset, cells) => {
+            transpile_stat(&Stat::PtrMove(*offset), known, f)?;
+            for (i, cell) in cells.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ">")?;
+                }
+                write!(f, "[-]")?;
+                add(i32::from(*cell), f)?;
+            }
+            transpile_stat(
+                &Stat::PtrMove(-offset - cells.len().saturating_sub(1) as i32),
+                known,
+                f,
+            )
+        }
+        Stat::Asm(_) => write!(f, "#insert removed#"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    const SETTINGS: Settings = Settings {
+        pre: 0,
+        post: 0,
+        freestanding: None,
+        buffered: true,
+        source: None,
+        max_steps: None,
+    };
+
+    #[test]
+    fn expands_extended_operations() {
+        assert_eq!(
+            compile(
+                &BrainFuck(vec![
+                    Stat::PtrMove(3),
+                    Stat::PtrMove(-2),
+                    Stat::DerefOp(Op::Add, 4),
+                    Stat::DerefOp(Op::Add, -3),
+                    Stat::DerefOp(Op::Add, 255),
+                    Stat::DerefOp(Op::Mul, 0),
+                    Stat::SetCells(-1, vec![1, 255]),
+                ]),
+                &SETTINGS
+            ),
+            ">>><<++++----[-]<[-]+>[-]-\n"
+        );
+    }
+
+    #[test]
+    fn expands_multiply_adds_to_loops() {
+        assert_eq!(
+            compile(
+                &BrainFuck(vec![
+                    Stat::Input,
+                    Stat::MulAdd(1, 3),
+                    Stat::SetCells(3, vec![2]),
+                    Stat::MulAdd(-1, -1),
+                    Stat::SetCells(0, vec![0]),
+                    Stat::Output,
+                ]),
+                &SETTINGS
+            ),
+            ",>>>[-]++<<<[->+++<<->].\n"
+        );
+    }
+
+    #[test]
+    fn restores_cells_after_constant_output() {
+        assert_eq!(
+            compile(
+                &BrainFuck(vec![
+                    Stat::OutputConst(vec![2]),
+                    Stat::Input,
+                    Stat::SetCells(0, vec![3]),
+                    Stat::OutputConst(vec![1, 4]),
+                ]),
+                &SETTINGS
+            ),
+            "++.--,[-]+++--.+++.-\n"
+        );
+    }
+
+    #[test]
+    fn stubs_out_inserts() {
+        assert_eq!(
+            compile(&parse("+::ldr r3, =345::[-]").unwrap(), &SETTINGS),
+            "+#insert removed#[-]\n"
+        );
+    }
+
+    #[test]
+    fn round_trips_through_parser() {
+        let source = ",>,[<->-]<++++++++++++++++++++++++++++++++++++++++++++++++.";
+        let bf = parse(source).unwrap();
+        assert_eq!(compile(&bf, &SETTINGS), format!("{}\n", source));
+        assert_eq!(parse(&compile(&bf, &SETTINGS)).unwrap(), bf);
+    }
+}
@@ -0,0 +1,315 @@
+//! Transpilation to C.
+//!
+//! Freestanding programs do not use libc, and start from a `_start` stub
+//! (for RISC-V and AArch64) which sets up the stack and calls `bf_main`. To
+//! build with the linker script:
+//! ```text
+//! gcc -ffreestanding -nostdlib -fno-tree-loop-distribute-patterns -T prog.ld prog.c io.c -o prog
+//! ```
+//!
+//! With a step limit, steps are used up as in the interpreter (by every
+//! statement, and every iteration of a loop or scan), and the program stops
+//! when none are left (exiting with the interpreter's exit code for the step
+//! limit, or halting if freestanding).
+
+use std::fmt::{Formatter, FormattingOptions};
+
+use super::{Freestanding, Hook, Settings};
+use crate::intermediate::{BrainFuck, Op, Stat};
+
+/// Startup code for freestanding programs, placed first by the linker script.
+const START: &str = r#"#if defined(__riscv)
+__asm__(".section .text._start\n.globl _start\n_start:\n\tla sp, __stack_top\n\tcall bf_main\n\t.text\n");
+#elif defined(__aarch64__)
+__asm__(".section .text._start\n.globl _start\n_start:\n\tldr x0, =__stack_top\n\tmov sp, x0\n\tbl bf_main\n\t.text\n");
+#else
+#error "no _start for this architecture, provide one that sets the stack and calls bf_main"
+#endif"#;
+
+/// The exit code of programs reaching the step limit (the same as the
+/// interpreter's).
+const STEP_LIMIT_EXIT: i32 = 106;
+
+/// Use up a step, before a statement or after a loop iteration.
+const STEP: &str = "if (!steps--) step_limit();";
+
+/// Compile the brainfuck program, given pre bytes of memory before the
+/// starting position, and post bytes after.
+pub fn compile(BrainFuck(stats): &BrainFuck, settings: &Settings) -> String {
+    let Settings {
+        pre,
+        post,
+        freestanding,
+        buffered,
+        max_steps,
+        ..
+    } = settings;
+    let mut result = String::new();
+    let mut f = Formatter::new(&mut result, FormattingOptions::new());
+
+    // a leading tape initialisation (possibly after constant output, which
+    // doesn't use the tape) can be done by the array initialiser (unless the
+    // tape is at a fixed address)
+    let fixed_tape = freestanding
+        .as_ref()
+        .and_then(|freestanding| freestanding.tape_address);
+    let first = stats
+        .iter()
+        .position(|stat| !matches!(stat, Stat::OutputConst(_)));
+    let (init, skipped) = match first.map(|i| (i, &stats[i])) {
+        Some((i, Stat::SetCells(offset, cells))) if fixed_tape.is_none() => (
+            format!("[{}] = {}", *pre as i32 + offset, char_list(cells)),
+            Some(i),
+        ),
+        _ => ("0".to_string(), None),
+    };
+    let ptr_init = if *pre == 0 {
+        "".to_string()
+    } else {
+        format!(" + {}", pre)
+    };
+
+    match freestanding {
+        None => {
+            writeln!(
+                f,
+                "#define _GNU_SOURCE\n#include <stdio.h>\n#include <string.h>"
+            )
+            .expect("Writing to string");
+            if let Some(steps) = max_steps {
+                writeln!(
+                    f,
+                    "#include <stdlib.h>\nstatic unsigned long long steps = {};\nstatic void step_limit(void) {{\n\tfflush(stdout);\n\tfputs(\"The step limit was reached\\n\", stderr);\n\texit({});\n}}",
+                    steps, STEP_LIMIT_EXIT
+                )
+                .expect("Writing to string");
+            }
+            writeln!(
+                f,
+                "int main(int argc, char **argv) {{\n\tchar cells[{}] = {{{}}};\n\tchar* ptr = cells{};\n\tsetvbuf(stdout, NULL, {});",
+                pre + post,
+                init,
+                ptr_init,
+                if *buffered { "_IOLBF, BUFSIZ" } else { "_IONBF, 0" }
+            )
+        }
+        Some(freestanding) => {
+            writeln!(f, "{}", START).expect("Writing to string");
+            if let Hook::Extern(name) = &freestanding.output {
+                writeln!(f, "extern void {}(char c);", name).expect("Writing to string");
+            }
+            if let Hook::Extern(name) = &freestanding.input {
+                writeln!(f, "extern char {}(void);", name).expect("Writing to string");
+            }
+            if let Some(steps) = max_steps {
+                writeln!(
+                    f,
+                    "static unsigned long long steps = {};\nstatic void step_limit(void) {{\n\tfor (;;) {{}}\n}}",
+                    steps
+                )
+                .expect("Writing to string");
+            }
+            match fixed_tape {
+                Some(address) => writeln!(
+                    f,
+                    "void bf_main(void) {{\n\tchar *const cells = (char *){:#x};\n\tfor (unsigned long i = 0; i < {}; i++) cells[i] = 0;\n\tchar* ptr = cells{};",
+                    address,
+                    pre + post,
+                    ptr_init
+                ),
+                None => writeln!(
+                    f,
+                    "static char cells[{}] = {{{}}};\nvoid bf_main(void) {{\n\tchar* ptr = cells{};",
+                    pre + post,
+                    init,
+                    ptr_init
+                ),
+            }
+        }
+    }
+    .expect("Writing to string");
+
+    for (i, stat) in stats.iter().enumerate() {
+        // the skipped initialisation still uses up its step
+        if Some(i) == skipped {
+            if max_steps.is_some() {
+                writeln!(f, "\t{}", STEP).expect("Writing to string");
+            }
+            continue;
+        }
+        transpile_stat(
+            stat,
+            1,
+            freestanding.as_ref(),
+            *buffered,
+            max_steps.is_some(),
+            &mut f,
+        )
+        .expect("Writing to string");
+    }
+
+    if freestanding.is_some() {
+        writeln!(f, "\tfor (;;) {{}}").expect("Writing to string");
+    }
+    writeln!(f, "}}").expect("Writing to string");
+
+    result
+}
+
+/// Comma separated cell values (as signed chars).
+fn char_list(cells: &[u8]) -> String {
+    cells
+        .iter()
+        .map(|cell| (*cell as i8).to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A C string literal of some bytes, with escapes for anything other than
+/// printable ASCII (and for `?`, to avoid trigraphs).
+fn string_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' | b'?' => literal.push_str(&format!("\\{}", *byte as char)),
+            b' '..=b'~' => literal.push(*byte as char),
+            _ => literal.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Write code inserted into the program (an insert or I/O template), keeping
+/// the indentation.
+fn insert(code: &str, indent: &str, f: &mut Formatter<'_>) -> std::fmt::Result {
+    for c in code.chars() {
+        if c == '\n' {
+            write!(f, "\n{}", indent)?;
+        } else {
+            write!(f, "{}", c)?;
+        }
+    }
+    Ok(())
+}
+
+fn transpile_stat(
+    stat: &Stat,
+    indent_lvl: usize,
+    freestanding: Option<&Freestanding>,
+    buffered: bool,
+    limited: bool,
+    f: &mut Formatter<'_>,
+) -> std::fmt::Result {
+    let indent = "\t".repeat(indent_lvl);
+    if limited {
+        writeln!(f, "{}{}", indent, STEP)?;
+    }
+    write!(f, "{}", indent)?;
+    match stat {
+        Stat::PtrMove(i @ i32::MIN..=-1) => writeln!(f, "ptr -= {};", i * -1),
+        Stat::PtrMove(i @ 1..=i32::MAX) => writeln!(f, "ptr += {};", i),
+        Stat::PtrMove(0) => writeln!(f, "/* redundant ptr move*/"),
+        Stat::DerefOp(op, i) => writeln!(
+            f,
+            "*ptr {}= {};",
+            match op {
+                Op::Add => "+",
+                Op::Mul => "*",
+                Op::Div => "/",
+                Op::Mod => "%",
+            },
+            i
+        ),
+        Stat::MulAdd(offset, factor) => {
+            writeln!(f, "if (*ptr) ptr[{}] += *ptr * {};", offset, factor)
+        }
+        Stat::SetCells(offset, cells) if freestanding.is_some() => {
+            // no memcpy without libc
+            for (i, cell) in cells.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "ptr[{}] = {};", offset + i as i32, *cell as i8)?;
+            }
+            writeln!(f)
+        }
+        Stat::SetCells(offset, cells) => writeln!(
+            f,
+            "memcpy(ptr + {}, (char[]){{{}}}, {});",
+            offset,
+            char_list(cells),
+            cells.len()
+        ),
+        Stat::Output => match freestanding.map(|freestanding| &freestanding.output) {
+            None => writeln!(f, "putchar(*ptr);"),
+            Some(Hook::Extern(name)) => writeln!(f, "{}(*ptr);", name),
+            Some(Hook::Template(code)) => {
+                insert(code, &indent, f)?;
+                writeln!(f)
+            }
+        },
+        Stat::OutputConst(bytes) if freestanding.is_some() => {
+            // the hooks output the current cell, so shadow the pointer with one
+            // to each byte in turn
+            writeln!(
+                f,
+                "for (char *ptr = (char[]){{{}}}, *end = ptr + {}; ptr < end; ptr++) {{",
+                char_list(bytes),
+                bytes.len()
+            )?;
+            // the bytes are output by a single statement
+            transpile_stat(
+                &Stat::Output,
+                indent_lvl + 1,
+                freestanding,
+                buffered,
+                false,
+                f,
+            )?;
+            writeln!(f, "{}}}", indent)
+        }
+        Stat::OutputConst(bytes) => writeln!(
+            f,
+            "fwrite({}, 1, {}, stdout);",
+            string_literal(bytes),
+            bytes.len()
+        ),
+        Stat::Input => match freestanding.map(|freestanding| &freestanding.input) {
+            None if buffered => writeln!(f, "fflush(stdout);\n{}*ptr = getchar();", indent),
+            None => writeln!(f, "*ptr = getchar();"),
+            Some(Hook::Extern(name)) => writeln!(f, "*ptr = {}();", name),
+            Some(Hook::Template(code)) => {
+                insert(code, &indent, f)?;
+                writeln!(f)
+            }
+        },
+        // memrchr is a GNU extension, hence _GNU_SOURCE (the iterations are
+        // not counted, so not used with a step limit)
+        Stat::ScanZero(1) if freestanding.is_none() && !limited => {
+            writeln!(f, "ptr = memchr(ptr, 0, cells + sizeof cells - ptr);")
+        }
+        Stat::ScanZero(-1) if freestanding.is_none() && !limited => {
+            writeln!(f, "ptr = memrchr(cells, 0, ptr - cells + 1);")
+        }
+        Stat::ScanZero(stride) if limited => {
+            writeln!(f, "while(*ptr) {{ ptr += {}; {} }}", stride, STEP)
+        }
+        Stat::ScanZero(stride) => writeln!(f, "while(*ptr) ptr += {};", stride),
+        Stat::WhileNonZero(stats) => {
+            writeln!(f, "while(*ptr) {{")?;
+            for stat in stats {
+                transpile_stat(stat, indent_lvl + 1, freestanding, buffered, limited, f)?;
+            }
+            if limited {
+                writeln!(f, "{}\t{}", indent, STEP)?;
+            }
+            writeln!(f, "{}}}", indent)
+        }
+        Stat::Asm(asm) => {
+            write!(f, "/* Start of inserted section*/\n{}", indent)?;
+            insert(asm, &indent, f)?;
+            writeln!(f, "/* End of inserted section */")
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! Direct compilation to a static x86-64 Linux executable.
+//!
+//! Machine code is written into a minimal ELF file with two segments: the
+//! headers and code (read/execute), and the tape (read/write, zero
+//! initialised and not stored in the file). No assembler, linker or C
+//! compiler is required.
+//!
+//! `rbx` holds the pointer (it is preserved by syscalls). Input and output use
+//! the `read` and `write` syscalls, with end of input setting the cell to 255
+//! (as in the other backends). Output is buffered (unless disabled) with the
+//! length in `r12`, flushed on newline, before input and at exit.
+//!
+//! The program is laid out from its control flow graph, with each block
+//! falling through to the next where possible. Scans for a zero cell (`[>]`
+//! and `[<]`) check 16 cells at a time with SSE2, so the tape is padded to
+//! keep these reads in bounds.
+//!
+//! Inserts are assembly text, so cannot be placed in the executable.
+
+use super::Settings;
+use crate::intermediate::{
+    cfg::{Cfg, Terminator},
+    BrainFuck, Op, Stat,
+};
+
+/// The address the file (headers and code) is loaded at.
+const CODE_ADDR: u64 = 0x400000;
+/// The address of the start of the tape.
+const TAPE_ADDR: u64 = 0x10000000;
+/// The output buffer is placed directly before the tape.
+const BUFFER_SIZE: u32 = 4096;
+const BUFFER_ADDR: u64 = TAPE_ADDR - BUFFER_SIZE as u64;
+/// Cells after the tape read by scans.
+const SCAN_PADDING: u32 = 16;
+const ELF_HEADER_SIZE: u16 = 64;
+const PROGRAM_HEADER_SIZE: u16 = 56;
+const PROGRAM_HEADERS: u16 = 2;
+
+/// Machine code being assembled, with the positions of the output buffering
+/// routines (if buffered).
+struct Code(Vec<u8>, Option<Routines>);
+
+/// The positions of the routines to add the byte in `al` to the output buffer
+/// (flushing it on newline or when full), and to flush the output buffer.
+#[derive(Clone, Copy)]
+struct Routines {
+    put: usize,
+    flush: usize,
+}
+
+impl Code {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes)
+    }
+
+    fn imm32(&mut self, i: i32) {
+        self.bytes(&i.to_le_bytes())
+    }
+
+    /// Write a syscall on the current cell (read or write, with one byte).
+    fn syscall(&mut self, number: u8, fd: u8) {
+        // mov eax, number; mov edi, fd; mov rsi, rbx; mov edx, 1; syscall
+        self.bytes(&[0xB8, number, 0, 0, 0, 0xBF, fd, 0, 0, 0]);
+        self.bytes(&[0x48, 0x89, 0xDE, 0xBA, 1, 0, 0, 0, 0x0F, 0x05]);
+    }
+
+    /// Call a routine at a position.
+    fn call(&mut self, target: usize) {
+        self.bytes(&[0xE8]);
+        self.imm32(target as i32 - (self.0.len() + 4) as i32)
+    }
+
+    /// Write the output buffering routines.
+    fn routines(&mut self) -> Routines {
+        let flush = self.0.len();
+        // test r12, r12; jz ret
+        self.bytes(&[0x4D, 0x85, 0xE4, 0x74, 23]);
+        // mov eax, 1 (write); mov edi, 1 (stdout); mov esi, buffer
+        self.bytes(&[0xB8, 1, 0, 0, 0, 0xBF, 1, 0, 0, 0, 0xBE]);
+        self.imm32(BUFFER_ADDR as i32);
+        // mov rdx, r12; syscall; xor r12d, r12d; ret
+        self.bytes(&[0x4C, 0x89, 0xE2, 0x0F, 0x05, 0x45, 0x31, 0xE4, 0xC3]);
+
+        let put = self.0.len();
+        // mov [r12 + buffer], al; inc r12
+        self.bytes(&[0x41, 0x88, 0x84, 0x24]);
+        self.imm32(BUFFER_ADDR as i32);
+        self.bytes(&[0x49, 0xFF, 0xC4]);
+        // cmp al, 10; je flush
+        self.bytes(&[0x3C, 0x0A, 0x0F, 0x84]);
+        self.imm32(flush as i32 - (self.0.len() + 4) as i32);
+        // cmp r12, size; je flush
+        self.bytes(&[0x49, 0x81, 0xFC]);
+        self.imm32(BUFFER_SIZE as i32);
+        self.bytes(&[0x0F, 0x84]);
+        self.imm32(flush as i32 - (self.0.len() + 4) as i32);
+        // ret
+        self.bytes(&[0xC3]);
+
+        Routines { put, flush }
+    }
+
+    /// Write a jump with a placeholder offset, returning the position of the
+    /// offset.
+    fn jump(&mut self, opcode: &[u8]) -> usize {
+        self.bytes(opcode);
+        self.imm32(0);
+        self.0.len() - 4
+    }
+
+    /// Set the offset of a jump, to jump to a position.
+    fn patch(&mut self, offset: usize, target: usize) {
+        let rel = target as i32 - (offset + 4) as i32;
+        self.0[offset..offset + 4].copy_from_slice(&rel.to_le_bytes())
+    }
+
+    /// Write the blocks of a program in order, each ending with a branch or
+    /// the exit.
+    fn blocks(&mut self, Cfg(blocks): &Cfg) {
+        let mut starts = Vec::new();
+        let mut jumps = Vec::new();
+        for (id, block) in blocks.iter().enumerate() {
+            starts.push(self.0.len());
+            for stat in &block.stats {
+                self.stat(stat)
+            }
+            match block.terminator {
+                Terminator::Branch(nonzero, zero) => {
+                    // cmp byte [rbx], 0
+                    self.bytes(&[0x80, 0x3B, 0x00]);
+                    if nonzero == id + 1 {
+                        // je zero
+                        jumps.push((self.jump(&[0x0F, 0x84]), zero))
+                    } else {
+                        // jne nonzero, then jmp zero (unless next)
+                        jumps.push((self.jump(&[0x0F, 0x85]), nonzero));
+                        if zero != id + 1 {
+                            jumps.push((self.jump(&[0xE9]), zero))
+                        }
+                    }
+                }
+                Terminator::Halt => self.exit(),
+            }
+        }
+        for (offset, target) in jumps {
+            self.patch(offset, starts[target])
+        }
+    }
+
+    /// Flush the output (if buffered) and exit.
+    fn exit(&mut self) {
+        if let Some(Routines { flush, .. }) = self.1 {
+            self.call(flush)
+        }
+        // mov eax, 60 (exit); xor edi, edi; syscall
+        self.bytes(&[0xB8, 60, 0, 0, 0, 0x31, 0xFF, 0x0F, 0x05]);
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        match stat {
+            Stat::PtrMove(0) => (),
+            Stat::PtrMove(i) => {
+                // add rbx, imm32
+                self.bytes(&[0x48, 0x81, 0xC3]);
+                self.imm32(*i)
+            }
+            Stat::DerefOp(Op::Add, i) => {
+                // add byte [rbx], imm8
+                self.bytes(&[0x80, 0x03, i.rem_euclid(256) as u8])
+            }
+            Stat::DerefOp(Op::Mul, i) => {
+                // movzx eax, byte [rbx]; imul eax, eax, imm32; mov [rbx], al
+                self.bytes(&[0x0F, 0xB6, 0x03, 0x69, 0xC0]);
+                self.imm32(*i);
+                self.bytes(&[0x88, 0x03])
+            }
+            Stat::MulAdd(offset, factor) => {
+                // movzx eax, byte [rbx]; test eax, eax; jz +12;
+                // imul eax, eax, imm32; add [rbx + disp32], al
+                self.bytes(&[0x0F, 0xB6, 0x03, 0x85, 0xC0, 0x74, 0x0C, 0x69, 0xC0]);
+                self.imm32(*factor);
+                self.bytes(&[0x00, 0x83]);
+                self.imm32(*offset)
+            }
+            Stat::DerefOp(Op::Div | Op::Mod, i) if i.rem_euclid(256) == 0 => {
+                // mov byte [rbx], 0
+                self.bytes(&[0xC6, 0x03, 0x00])
+            }
+            Stat::DerefOp(op, i) => {
+                // movzx eax, byte [rbx]; xor edx, edx; mov ecx, imm32; div ecx
+                self.bytes(&[0x0F, 0xB6, 0x03, 0x31, 0xD2, 0xB9]);
+                self.imm32(i.rem_euclid(256));
+                self.bytes(&[0xF7, 0xF1]);
+                // mov [rbx], al (quotient) or mov [rbx], dl (remainder)
+                self.bytes(&[0x88, if *op == Op::Div { 0x03 } else { 0x13 }])
+            }
+            Stat::Output => match self.1 {
+                Some(Routines { put, .. }) => {
+                    // mov al, [rbx]
+                    self.bytes(&[0x8A, 0x03]);
+                    self.call(put)
+                }
+                None => self.syscall(1, 1),
+            },
+            Stat::OutputConst(bytes) => {
+                if let Some(Routines { flush, .. }) = self.1 {
+                    self.call(flush)
+                }
+                // mov eax, 1 (write); mov edi, 1 (stdout); lea rsi, [rip + 12]
+                self.bytes(&[0xB8, 1, 0, 0, 0, 0xBF, 1, 0, 0, 0, 0x48, 0x8D, 0x35]);
+                self.imm32(12);
+                // mov edx, len; syscall; jmp over the bytes
+                self.bytes(&[0xBA]);
+                self.imm32(bytes.len() as i32);
+                self.bytes(&[0x0F, 0x05, 0xE9]);
+                self.imm32(bytes.len() as i32);
+                self.bytes(bytes)
+            }
+            Stat::Input => {
+                if let Some(Routines { flush, .. }) = self.1 {
+                    self.call(flush)
+                }
+                // mov byte [rbx], 255 (kept if no byte is read)
+                self.bytes(&[0xC6, 0x03, 0xFF]);
+                self.syscall(0, 0)
+            }
+            Stat::ScanZero(1) => {
+                // pxor xmm1, xmm1; movdqu xmm0, [rbx]; pcmpeqb xmm0, xmm1
+                self.bytes(&[0x66, 0x0F, 0xEF, 0xC9, 0xF3, 0x0F, 0x6F, 0x03]);
+                self.bytes(&[0x66, 0x0F, 0x74, 0xC1]);
+                // pmovmskb eax, xmm0; test eax, eax; jnz found
+                self.bytes(&[0x66, 0x0F, 0xD7, 0xC0, 0x85, 0xC0, 0x75, 0x06]);
+                // add rbx, 16; jmp movdqu
+                self.bytes(&[0x48, 0x83, 0xC3, 0x10, 0xEB, 0xEA]);
+                // found: bsf eax, eax; add rbx, rax
+                self.bytes(&[0x0F, 0xBC, 0xC0, 0x48, 0x01, 0xC3])
+            }
+            Stat::ScanZero(-1) => {
+                // pxor xmm1, xmm1; movdqu xmm0, [rbx - 15]; pcmpeqb xmm0, xmm1
+                self.bytes(&[0x66, 0x0F, 0xEF, 0xC9, 0xF3, 0x0F, 0x6F, 0x43, 0xF1]);
+                self.bytes(&[0x66, 0x0F, 0x74, 0xC1]);
+                // pmovmskb eax, xmm0; test eax, eax; jnz found
+                self.bytes(&[0x66, 0x0F, 0xD7, 0xC0, 0x85, 0xC0, 0x75, 0x06]);
+                // sub rbx, 16; jmp movdqu
+                self.bytes(&[0x48, 0x83, 0xEB, 0x10, 0xEB, 0xE9]);
+                // found: bsr eax, eax; lea rbx, [rbx + rax - 15]
+                self.bytes(&[0x0F, 0xBD, 0xC0, 0x48, 0x8D, 0x5C, 0x03, 0xF1])
+            }
+            Stat::ScanZero(stride) => {
+                // jmp cmp; add rbx, imm32
+                self.bytes(&[0xEB, 0x07, 0x48, 0x81, 0xC3]);
+                self.imm32(*stride);
+                // cmp byte [rbx], 0; jne add
+                self.bytes(&[0x80, 0x3B, 0x00, 0x75, 0xF4])
+            }
+            Stat::WhileNonZero(_) => unreachable!("loops are lowered to branches"),
+            Stat::SetCells(offset, cells) => {
+                for (i, cell) in cells.iter().enumerate() {
+                    // mov byte [rbx + disp32], imm8
+                    self.bytes(&[0xC6, 0x83]);
+                    self.imm32(offset + i as i32);
+                    self.bytes(&[*cell])
+                }
+            }
+            Stat::Asm(_) => unreachable!("inserts are rejected before compiling"),
+        }
+    }
+}
+
+/// Find the first insert in some statements.
+fn first_insert(stats: &[Stat]) -> Option<&str> {
+    stats.iter().find_map(|stat| match stat {
+        Stat::Asm(asm) => Some(&asm[..]),
+        Stat::WhileNonZero(stats) => first_insert(stats),
+        _ => None,
+    })
+}
+
+/// Build an executable for the brainfuck program, given pre bytes of memory
+/// before the starting position, and post bytes after. Fails with the text of
+/// the first insert in the program.
+pub fn build<'a>(bf: &'a BrainFuck, settings: &Settings) -> Result<Vec<u8>, &'a str> {
+    let Settings {
+        pre,
+        post,
+        buffered,
+        ..
+    } = *settings;
+    if let Some(asm) = first_insert(&bf.0) {
+        return Err(asm);
+    }
+    let cfg = Cfg::from(bf);
+    cfg.verify()
+        .unwrap_or_else(|error| panic!("invalid control flow graph: {}", error));
+    let mut code = Code(Vec::new(), None);
+
+    // the routines are placed before the entry point
+    if buffered {
+        code.1 = Some(code.routines());
+    }
+    let entry = code.0.len();
+
+    // xor r12d, r12d (empty buffer); mov rbx, imm64
+    if buffered {
+        code.bytes(&[0x45, 0x31, 0xE4]);
+    }
+    code.bytes(&[0x48, 0xBB]);
+    code.bytes(&(TAPE_ADDR + u64::from(pre)).to_le_bytes());
+
+    code.blocks(&cfg);
+
+    let headers_size = u64::from(ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * PROGRAM_HEADERS);
+    let file_size = headers_size + code.0.len() as u64;
+
+    let mut elf = Code(Vec::new(), None);
+
+    // ELF header: 64 bit, little endian, version 1, System V ABI
+    elf.bytes(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // executable, x86-64, version 1
+    elf.bytes(&2u16.to_le_bytes());
+    elf.bytes(&0x3Eu16.to_le_bytes());
+    elf.bytes(&1u32.to_le_bytes());
+    // entry, program header offset, section header offset, flags
+    elf.bytes(&(CODE_ADDR + headers_size + entry as u64).to_le_bytes());
+    elf.bytes(&u64::from(ELF_HEADER_SIZE).to_le_bytes());
+    elf.bytes(&0u64.to_le_bytes());
+    elf.bytes(&0u32.to_le_bytes());
+    // header sizes and counts (no section headers)
+    for size in [
+        ELF_HEADER_SIZE,
+        PROGRAM_HEADER_SIZE,
+        PROGRAM_HEADERS,
+        0,
+        0,
+        0,
+    ] {
+        elf.bytes(&size.to_le_bytes());
+    }
+
+    // program headers: loadable, flags, offset, virtual & physical address,
+    // file & memory size, alignment
+    for (flags, addr, file_size, mem_size) in [
+        (0b101u32, CODE_ADDR, file_size, file_size),
+        (
+            0b110u32,
+            BUFFER_ADDR,
+            0,
+            u64::from(BUFFER_SIZE + pre + post + SCAN_PADDING),
+        ),
+    ] {
+        elf.bytes(&1u32.to_le_bytes());
+        elf.bytes(&flags.to_le_bytes());
+        elf.bytes(&0u64.to_le_bytes());
+        for field in [addr, addr, file_size, mem_size, 0x1000] {
+            elf.bytes(&field.to_le_bytes());
+        }
+    }
+
+    elf.bytes(&code.0);
+    Ok(elf.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    const UNBUFFERED: Settings = Settings {
+        pre: 0,
+        post: 10,
+        freestanding: None,
+        buffered: false,
+        source: None,
+        max_steps: None,
+    };
+
+    #[test]
+    fn writes_elf_headers() {
+        let elf = build(&parse("+.").unwrap(), &UNBUFFERED).unwrap();
+        assert_eq!(&elf[..4], b"\x7FELF");
+        assert_eq!(
+            elf.len(),
+            usize::from(ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * PROGRAM_HEADERS) + 10 + 3 + 20 + 9
+        );
+    }
+
+    #[test]
+    fn patches_loop_jumps() {
+        let mut code = Code(Vec::new(), None);
+        code.blocks(&Cfg::from(&parse("[-]").unwrap()));
+        assert_eq!(
+            &code.0[..21],
+            &[
+                0x80, 0x3B, 0x00, 0x0F, 0x84, 12, 0, 0, 0, // je +12
+                0x80, 0x03, 0xFF, // sub
+                0x80, 0x3B, 0x00, 0x0F, 0x85, 0xF4, 0xFF, 0xFF, 0xFF // jne -12
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_inserts() {
+        assert_eq!(build(&parse("+::nop::").unwrap(), &UNBUFFERED), Err("nop"));
+    }
+}
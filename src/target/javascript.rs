@@ -0,0 +1,215 @@
+//! Transpilation to a JavaScript module.
+//!
+//! The module exports `run(io)`, taking an object with `read()` (returning a
+//! byte, or `undefined` at the end of input), `write(byte)` and
+//! `writeAll(bytes)` (writing known output, as a `Uint8Array`), so it can be
+//! used from a browser. When run directly by node, stdin and stdout are used
+//! (with output buffered unless disabled).
+//! Inserts are placed as raw JavaScript, with `cells`, `ptr` and `io` in
+//! scope.
+//!
+//! To run:
+//! ```text
+//! node prog.mjs
+//! ```
+
+use std::fmt::{Formatter, FormattingOptions};
+
+use super::Settings;
+use crate::intermediate::{BrainFuck, Op, Stat};
+
+/// Runs the module with stdin and stdout when it is the node entry point,
+/// buffering output (unless disabled) until a newline, input or the end of the
+/// program.
+const NODE_MAIN: &str = "if (typeof process !== \"undefined\" && process.argv?.[1]) {
+	const { pathToFileURL } = await import(\"url\");
+	if (import.meta.url === pathToFileURL(process.argv[1]).href) {
+		const fs = await import(\"fs\");
+		const byte = new Uint8Array(1);
+		let output = [];
+		const flush = () => {
+			fs.writeSync(1, Uint8Array.from(output));
+			output = [];
+		};
+		run({
+			read: () => {
+				flush();
+				try {
+					return fs.readSync(0, byte, 0, 1) === 1 ? byte[0] : undefined;
+				} catch {
+					return undefined;
+				}
+			},
+			write: (value) => {
+				output.push(value);
+				{flush}
+			},
+			writeAll: (bytes) => {
+				output.push(...bytes);
+				{flush_all}
+			},
+		});
+		flush();
+	}
+}";
+
+/// Compile the brainfuck program, given pre bytes of memory before the
+/// starting position, and post bytes after.
+pub fn compile(BrainFuck(stats): &BrainFuck, settings: &Settings) -> String {
+    let Settings {
+        pre,
+        post,
+        buffered,
+        ..
+    } = settings;
+    let mut result = String::new();
+    let mut f = Formatter::new(&mut result, FormattingOptions::new());
+
+    writeln!(
+        f,
+        "export function run(io) {{\n\tconst cells = new Uint8Array({});\n\tlet ptr = {};",
+        pre + post,
+        pre
+    )
+    .expect("Writing to string");
+
+    for stat in stats {
+        transpile_stat(stat, 1, &mut f).expect("Writing to string");
+    }
+
+    // unbuffered output is flushed after every write
+    let (flush, flush_all) = if *buffered {
+        (
+            "if (value === 10) {\n\t\t\t\t\tflush();\n\t\t\t\t}",
+            "if (bytes.includes(10)) {\n\t\t\t\t\tflush();\n\t\t\t\t}",
+        )
+    } else {
+        ("flush();", "flush();")
+    };
+    writeln!(
+        f,
+        "}}\n\n{}",
+        NODE_MAIN
+            .replace("{flush}", flush)
+            .replace("{flush_all}", flush_all)
+    )
+    .expect("Writing to string");
+
+    result
+}
+
+fn transpile_stat(stat: &Stat, indent_lvl: usize, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let indent = "\t".repeat(indent_lvl);
+    write!(f, "{}", indent)?;
+    match stat {
+        Stat::PtrMove(i @ i32::MIN..=-1) => writeln!(f, "ptr -= {};", -(*i as i64)),
+        Stat::PtrMove(i @ 1..=i32::MAX) => writeln!(f, "ptr += {};", i),
+        Stat::PtrMove(0) => writeln!(f, "/* redundant ptr move*/"),
+        // the typed array wraps values on assignment
+        Stat::DerefOp(op, i) => writeln!(
+            f,
+            "cells[ptr] {}= {};",
+            match op {
+                Op::Add => "+",
+                Op::Mul => "*",
+                Op::Div => "/",
+                Op::Mod => "%",
+            },
+            i.rem_euclid(256)
+        ),
+        Stat::MulAdd(offset, factor) => writeln!(
+            f,
+            "if (cells[ptr]) cells[ptr + {}] += cells[ptr] * {};",
+            offset,
+            factor.rem_euclid(256)
+        ),
+        Stat::SetCells(offset, cells) => writeln!(
+            f,
+            "cells.set([{}], ptr + {});",
+            cells
+                .iter()
+                .map(|cell| cell.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            offset
+        ),
+        Stat::Output => writeln!(f, "io.write(cells[ptr]);"),
+        Stat::OutputConst(bytes) => writeln!(
+            f,
+            "io.writeAll(Uint8Array.of({}));",
+            bytes
+                .iter()
+                .map(|byte| byte.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Stat::Input => writeln!(f, "cells[ptr] = io.read() ?? 255;"),
+        Stat::ScanZero(1) => writeln!(f, "ptr = cells.indexOf(0, ptr);"),
+        Stat::ScanZero(-1) => writeln!(f, "ptr = cells.lastIndexOf(0, ptr);"),
+        Stat::ScanZero(stride) => writeln!(f, "while (cells[ptr]) ptr += {};", stride),
+        Stat::WhileNonZero(stats) => {
+            writeln!(f, "while (cells[ptr]) {{")?;
+            for stat in stats {
+                transpile_stat(stat, indent_lvl + 1, f)?;
+            }
+            writeln!(f, "{}}}", indent)
+        }
+        Stat::Asm(asm) => {
+            write!(f, "/* Start of inserted section*/\n{}", indent)?;
+            for c in asm.chars() {
+                if c == '\n' {
+                    write!(f, "\n{}", indent)?;
+                } else {
+                    write!(f, "{}", c)?;
+                }
+            }
+            writeln!(f, "\n{}/* End of inserted section */", indent)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn uses_injected_io() {
+        let BrainFuck(stats) = parse(",[.,]").unwrap();
+        let mut result = String::new();
+        let mut f = Formatter::new(&mut result, FormattingOptions::new());
+        for stat in &stats {
+            transpile_stat(stat, 1, &mut f).unwrap();
+        }
+        assert_eq!(
+            result,
+            "\tcells[ptr] = io.read() ?? 255;\n\twhile (cells[ptr]) {\n\t\tio.write(cells[ptr]);\n\t\tcells[ptr] = io.read() ?? 255;\n\t}\n"
+        );
+    }
+
+    #[test]
+    fn writes_constant_output_at_once() {
+        let mut result = String::new();
+        let mut f = Formatter::new(&mut result, FormattingOptions::new());
+        transpile_stat(&Stat::OutputConst(b"Hi".to_vec()), 1, &mut f).unwrap();
+        assert_eq!(result, "\tio.writeAll(Uint8Array.of(72, 105));\n");
+    }
+
+    #[test]
+    fn decides_flushing_when_compiling() {
+        let bf = parse("+.").unwrap();
+        let settings = |buffered| Settings {
+            pre: 0,
+            post: 10,
+            freestanding: None,
+            buffered,
+            source: None,
+            max_steps: None,
+        };
+        let buffered = compile(&bf, &settings(true));
+        assert!(buffered.contains("output.push(value);\n\t\t\t\tif (value === 10) {\n"));
+        let unbuffered = compile(&bf, &settings(false));
+        assert!(unbuffered.contains("output.push(value);\n\t\t\t\tflush();\n"));
+        assert!(!unbuffered.contains("value === 10"));
+    }
+}
@@ -0,0 +1,491 @@
+//! Direct compilation to a JVM class file.
+//!
+//! The class has a single `main` method, with the tape as a `byte[]` in local
+//! 1 and the pointer as an `int` in local 2. Input uses `System.in.read()`
+//! (which gives 255 once truncated at the end of input) and output uses
+//! `System.out.write()`, which is buffered and flushed on newline, and is also
+//! flushed before input and at exit (or after every write if unbuffered). Loops are lowered
+//! to branches, with a stack map frame at each branch target (required by the
+//! verifier since Java 7).
+//!
+//! Inserts are text, so cannot be placed in the class file.
+//!
+//! To run:
+//! ```text
+//! java -cp . Prog
+//! ```
+
+use std::collections::BTreeSet;
+
+use super::Settings;
+use crate::intermediate::{BrainFuck, Op, Stat};
+
+/// Java 8 (the stack map frames are required from Java 7).
+const MAJOR_VERSION: u16 = 52;
+/// The largest code attribute allowed in a method.
+const MAX_CODE: usize = 65535;
+/// The most bytes in a string constant, as constants are limited to 65535
+/// bytes of modified UTF-8 (with up to two per character).
+const MAX_STRING: usize = 32767;
+const LOCAL_CELLS: u8 = 1;
+const LOCAL_PTR: u8 = 2;
+
+/// Reasons a program cannot be compiled to a class file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error<'a> {
+    /// The program contains an insert, with its text.
+    Insert(&'a str),
+    /// The program is too large for a single method.
+    TooLarge,
+}
+
+/// The constant pool of a class, with entries numbered from 1.
+struct Pool {
+    bytes: Vec<u8>,
+    count: u16,
+}
+
+impl Pool {
+    fn entry(&mut self, tag: u8, data: &[u8]) -> u16 {
+        self.bytes.push(tag);
+        self.bytes.extend_from_slice(data);
+        self.count += 1;
+        self.count
+    }
+
+    fn utf8(&mut self, s: &str) -> u16 {
+        let mut data = (s.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(s.as_bytes());
+        self.entry(1, &data)
+    }
+
+    /// A string of the characters U+0000 to U+00FF, one per byte, in the
+    /// modified UTF-8 used by class files.
+    fn latin1(&mut self, bytes: &[u8]) -> u16 {
+        let mut encoded = Vec::new();
+        for byte in bytes {
+            match byte {
+                0x01..=0x7F => encoded.push(*byte),
+                _ => encoded.extend_from_slice(&[0xC0 | (byte >> 6), 0x80 | (byte & 0x3F)]),
+            }
+        }
+        let mut data = (encoded.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&encoded);
+        let utf8 = self.entry(1, &data);
+        self.entry(8, &utf8.to_be_bytes())
+    }
+
+    fn integer(&mut self, i: i32) -> u16 {
+        self.entry(3, &i.to_be_bytes())
+    }
+
+    fn class(&mut self, name: &str) -> u16 {
+        let name = self.utf8(name);
+        self.entry(7, &name.to_be_bytes())
+    }
+
+    /// A field or method reference (tag 9 or 10).
+    fn member(&mut self, tag: u8, class: u16, name: &str, descriptor: &str) -> u16 {
+        let name = self.utf8(name);
+        let descriptor = self.utf8(descriptor);
+        let mut name_and_type = name.to_be_bytes().to_vec();
+        name_and_type.extend_from_slice(&descriptor.to_be_bytes());
+        let name_and_type = self.entry(12, &name_and_type);
+        let mut data = class.to_be_bytes().to_vec();
+        data.extend_from_slice(&name_and_type.to_be_bytes());
+        self.entry(tag, &data)
+    }
+}
+
+/// The constant pool entries used by the code.
+struct Refs {
+    system_in: u16,
+    system_out: u16,
+    read: u16,
+    write: u16,
+    write_bytes: u16,
+    flush: u16,
+    get_bytes: u16,
+    charset: u16,
+}
+
+/// Bytecode being assembled, with the offsets of all branch targets.
+struct Code<'a> {
+    bytes: Vec<u8>,
+    targets: BTreeSet<usize>,
+    pool: &'a mut Pool,
+    refs: Refs,
+    buffered: bool,
+}
+
+impl<'b> Code<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes)
+    }
+
+    /// Push an integer constant.
+    fn push(&mut self, i: i32) {
+        match i {
+            -1..=5 => self.bytes(&[(0x03 + i) as u8]),
+            -128..=127 => self.bytes(&[0x10, i as u8]),
+            -32768..=32767 => {
+                self.bytes(&[0x11]);
+                self.bytes(&(i as i16).to_be_bytes())
+            }
+            _ => {
+                // ldc_w
+                let index = self.pool.integer(i);
+                self.bytes(&[0x13]);
+                self.bytes(&index.to_be_bytes())
+            }
+        }
+    }
+
+    /// Push the tape and the index of a cell at an offset from the pointer.
+    fn cell(&mut self, offset: i32) {
+        // aload_1; iload_2
+        self.bytes(&[0x2A + LOCAL_CELLS, 0x1A + LOCAL_PTR]);
+        if offset != 0 {
+            self.push(offset);
+            // iadd
+            self.bytes(&[0x60])
+        }
+    }
+
+    /// Push a constant from the pool (ldc_w).
+    fn load(&mut self, constant: u16) {
+        self.bytes(&[0x13]);
+        self.bytes(&constant.to_be_bytes())
+    }
+
+    fn invoke(&mut self, method: u16) {
+        // invokevirtual
+        self.bytes(&[0xB6]);
+        self.bytes(&method.to_be_bytes())
+    }
+
+    fn get_static(&mut self, field: u16) {
+        self.bytes(&[0xB2]);
+        self.bytes(&field.to_be_bytes())
+    }
+
+    /// Write a wide jump (goto_w) with a placeholder offset, returning the
+    /// position of the instruction.
+    fn jump(&mut self) -> usize {
+        self.bytes(&[0xC8, 0, 0, 0, 0]);
+        self.bytes.len() - 5
+    }
+
+    /// Set the offset of a jump, to jump to a position.
+    fn patch(&mut self, jump: usize, target: usize) {
+        let rel = target as i32 - jump as i32;
+        self.bytes[jump + 1..jump + 5].copy_from_slice(&rel.to_be_bytes());
+        self.targets.insert(target);
+    }
+
+    fn move_ptr(&mut self, i: i32) {
+        match i {
+            0 => (),
+            -128..=127 => self.bytes(&[0x84, LOCAL_PTR, i as u8]),
+            -32768..=32767 => {
+                // wide iinc
+                self.bytes(&[0xC4, 0x84, 0, LOCAL_PTR]);
+                self.bytes(&(i as i16).to_be_bytes())
+            }
+            _ => {
+                // iload_2; push; iadd; istore_2
+                self.bytes(&[0x1A + LOCAL_PTR]);
+                self.push(i);
+                self.bytes(&[0x60, 0x3B + LOCAL_PTR])
+            }
+        }
+    }
+
+    fn stats(&mut self, stats: &'b [Stat]) -> Result<(), Error<'b>> {
+        for stat in stats {
+            self.stat(stat)?
+        }
+        Ok(())
+    }
+
+    fn stat(&mut self, stat: &'b Stat) -> Result<(), Error<'b>> {
+        match stat {
+            Stat::PtrMove(i) => self.move_ptr(*i),
+            Stat::DerefOp(Op::Div | Op::Mod, i) if i.rem_euclid(256) == 0 => {
+                self.cell(0);
+                // iconst_0; bastore
+                self.bytes(&[0x03, 0x54])
+            }
+            Stat::DerefOp(op, i) => {
+                self.cell(0);
+                // dup2; baload
+                self.bytes(&[0x5C, 0x33]);
+                if let Op::Div | Op::Mod = op {
+                    // the cell is unsigned: sipush 255; iand
+                    self.bytes(&[0x11, 0x00, 0xFF, 0x7E]);
+                }
+                self.push(i.rem_euclid(256));
+                self.bytes(&[match op {
+                    Op::Add => 0x60,
+                    Op::Mul => 0x68,
+                    Op::Div => 0x6C,
+                    Op::Mod => 0x70,
+                }]);
+                // i2b; bastore
+                self.bytes(&[0x91, 0x54])
+            }
+            Stat::MulAdd(offset, factor) => {
+                self.cell(0);
+                // baload; ifeq end (the target is left alone when the cell is 0)
+                self.bytes(&[0x33, 0x99, 0, 0]);
+                let skip = self.bytes.len() - 3;
+                self.cell(*offset);
+                // dup2; baload
+                self.bytes(&[0x5C, 0x33]);
+                self.cell(0);
+                // baload
+                self.bytes(&[0x33]);
+                self.push(factor.rem_euclid(256));
+                // imul; iadd; i2b; bastore
+                self.bytes(&[0x68, 0x60, 0x91, 0x54]);
+                let end = self.bytes.len();
+                self.bytes[skip + 1..skip + 3]
+                    .copy_from_slice(&((end - skip) as i16).to_be_bytes());
+                self.targets.insert(end);
+            }
+            Stat::SetCells(offset, cells) => {
+                for (i, cell) in cells.iter().enumerate() {
+                    self.cell(offset + i as i32);
+                    self.push(*cell as i8 as i32);
+                    self.bytes(&[0x54])
+                }
+            }
+            Stat::Output => {
+                self.get_static(self.refs.system_out);
+                self.cell(0);
+                self.bytes(&[0x33]);
+                self.invoke(self.refs.write);
+                if !self.buffered {
+                    self.get_static(self.refs.system_out);
+                    self.invoke(self.refs.flush)
+                }
+            }
+            Stat::OutputConst(bytes) => {
+                for chunk in bytes.chunks(MAX_STRING) {
+                    // the bytes of a string constant, decoded as ISO-8859-1
+                    self.get_static(self.refs.system_out);
+                    let string = self.pool.latin1(chunk);
+                    self.load(string);
+                    self.load(self.refs.charset);
+                    self.invoke(self.refs.get_bytes);
+                    self.push(0);
+                    self.push(chunk.len() as i32);
+                    self.invoke(self.refs.write_bytes);
+                }
+                if !self.buffered {
+                    self.get_static(self.refs.system_out);
+                    self.invoke(self.refs.flush)
+                }
+            }
+            Stat::Input => {
+                self.get_static(self.refs.system_out);
+                self.invoke(self.refs.flush);
+                self.cell(0);
+                self.get_static(self.refs.system_in);
+                self.invoke(self.refs.read);
+                // i2b; bastore
+                self.bytes(&[0x91, 0x54])
+            }
+            Stat::ScanZero(stride) => {
+                let start = self.bytes.len();
+                self.targets.insert(start);
+                self.cell(0);
+                // baload; ifeq end
+                self.bytes(&[0x33, 0x99, 0, 0]);
+                let skip = self.bytes.len() - 3;
+                self.move_ptr(*stride);
+                // goto start
+                let repeat = self.bytes.len();
+                self.bytes(&[0xA7]);
+                self.bytes(&((start as i32 - repeat as i32) as i16).to_be_bytes());
+                let end = self.bytes.len();
+                self.bytes[skip + 1..skip + 3]
+                    .copy_from_slice(&((end - skip) as i16).to_be_bytes());
+                self.targets.insert(end);
+            }
+            Stat::WhileNonZero(stats) => {
+                let start = self.bytes.len();
+                self.targets.insert(start);
+                self.cell(0);
+                // baload; ifne +8 (over the goto_w to the end)
+                self.bytes(&[0x33, 0x9A, 0x00, 0x08]);
+                let skip = self.jump();
+                self.targets.insert(self.bytes.len());
+                self.stats(stats)?;
+                let repeat = self.jump();
+                self.patch(repeat, start);
+                let end = self.bytes.len();
+                self.patch(skip, end)
+            }
+            Stat::Asm(asm) => return Err(Error::Insert(asm)),
+        }
+        Ok(())
+    }
+}
+
+/// Build a class for the brainfuck program, given pre bytes of memory
+/// before the starting position, and post bytes after. Fails with the text of
+/// the first insert in the program, or if the program is too large.
+pub fn build<'a>(
+    BrainFuck(stats): &'a BrainFuck,
+    name: &str,
+    settings: &Settings,
+) -> Result<Vec<u8>, Error<'a>> {
+    let Settings {
+        pre,
+        post,
+        buffered,
+        ..
+    } = *settings;
+    let mut pool = Pool {
+        bytes: Vec::new(),
+        count: 0,
+    };
+    let this_class = pool.class(name);
+    let super_class = pool.class("java/lang/Object");
+    let main_name = pool.utf8("main");
+    let main_descriptor = pool.utf8("([Ljava/lang/String;)V");
+    let code_name = pool.utf8("Code");
+    let stack_map_name = pool.utf8("StackMapTable");
+    let args_class = pool.class("[Ljava/lang/String;");
+    let cells_class = pool.class("[B");
+    let system = pool.class("java/lang/System");
+    let input_stream = pool.class("java/io/InputStream");
+    let print_stream = pool.class("java/io/PrintStream");
+    let string = pool.class("java/lang/String");
+    let refs = Refs {
+        system_in: pool.member(9, system, "in", "Ljava/io/InputStream;"),
+        system_out: pool.member(9, system, "out", "Ljava/io/PrintStream;"),
+        read: pool.member(10, input_stream, "read", "()I"),
+        write: pool.member(10, print_stream, "write", "(I)V"),
+        write_bytes: pool.member(10, print_stream, "write", "([BII)V"),
+        flush: pool.member(10, print_stream, "flush", "()V"),
+        get_bytes: pool.member(10, string, "getBytes", "(Ljava/lang/String;)[B"),
+        charset: pool.latin1(b"ISO-8859-1"),
+    };
+
+    let mut code = Code {
+        bytes: Vec::new(),
+        targets: BTreeSet::new(),
+        pool: &mut pool,
+        refs,
+        buffered,
+    };
+
+    // newarray byte; astore_1
+    code.push((pre + post) as i32);
+    code.bytes(&[0xBC, 8, 0x4B + LOCAL_CELLS]);
+    // istore_2
+    code.push(pre as i32);
+    code.bytes(&[0x3B + LOCAL_PTR]);
+
+    code.stats(stats)?;
+
+    code.get_static(code.refs.system_out);
+    code.invoke(code.refs.flush);
+    // return
+    code.bytes(&[0xB1]);
+
+    let Code { bytes, targets, .. } = code;
+    if bytes.len() > MAX_CODE {
+        return Err(Error::TooLarge);
+    }
+
+    // every branch target has the same locals (args, cells, ptr) and an
+    // empty stack, so full frames are used
+    let mut stack_map = Vec::new();
+    let mut previous = None;
+    for target in &targets {
+        let delta = match previous {
+            None => *target,
+            Some(previous) => target - previous - 1,
+        };
+        previous = Some(*target);
+        stack_map.push(255);
+        stack_map.extend_from_slice(&(delta as u16).to_be_bytes());
+        stack_map.extend_from_slice(&3u16.to_be_bytes());
+        stack_map.push(7);
+        stack_map.extend_from_slice(&args_class.to_be_bytes());
+        stack_map.push(7);
+        stack_map.extend_from_slice(&cells_class.to_be_bytes());
+        // int, and no stack items
+        stack_map.extend_from_slice(&[1, 0, 0]);
+    }
+
+    let mut attributes = Vec::new();
+    if !targets.is_empty() {
+        attributes.extend_from_slice(&stack_map_name.to_be_bytes());
+        attributes.extend_from_slice(&(stack_map.len() as u32 + 2).to_be_bytes());
+        attributes.extend_from_slice(&(targets.len() as u16).to_be_bytes());
+        attributes.extend_from_slice(&stack_map);
+    }
+
+    let mut class = Vec::new();
+    class.extend_from_slice(&0xCAFEBABEu32.to_be_bytes());
+    class.extend_from_slice(&0u16.to_be_bytes());
+    class.extend_from_slice(&MAJOR_VERSION.to_be_bytes());
+    class.extend_from_slice(&(pool.count + 1).to_be_bytes());
+    class.extend_from_slice(&pool.bytes);
+    // public (and super), this, super, no interfaces or fields, one method
+    for field in [0x21, this_class, super_class, 0, 0, 1] {
+        class.extend_from_slice(&field.to_be_bytes());
+    }
+
+    // public static void main(String[]) with one (code) attribute
+    for field in [0x09, main_name, main_descriptor, 1, code_name] {
+        class.extend_from_slice(&field.to_be_bytes());
+    }
+    class.extend_from_slice(&((12 + bytes.len() + attributes.len()) as u32).to_be_bytes());
+    // max stack and locals
+    class.extend_from_slice(&6u16.to_be_bytes());
+    class.extend_from_slice(&3u16.to_be_bytes());
+    class.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    class.extend_from_slice(&bytes);
+    // no exception table, the stack map table if there are branches
+    class.extend_from_slice(&0u16.to_be_bytes());
+    class.extend_from_slice(&(u16::from(!targets.is_empty())).to_be_bytes());
+    class.extend_from_slice(&attributes);
+
+    // no class attributes
+    class.extend_from_slice(&0u16.to_be_bytes());
+    Ok(class)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    const SETTINGS: Settings = Settings {
+        pre: 0,
+        post: 10,
+        freestanding: None,
+        buffered: true,
+        source: None,
+        max_steps: None,
+    };
+
+    #[test]
+    fn writes_class_header() {
+        let class = build(&parse("+[-].").unwrap(), "Test", &SETTINGS).unwrap();
+        assert_eq!(&class[..8], &[0xCA, 0xFE, 0xBA, 0xBE, 0, 0, 0, 52]);
+    }
+
+    #[test]
+    fn rejects_inserts() {
+        assert_eq!(
+            build(&parse("+::x++;::").unwrap(), "Test", &SETTINGS),
+            Err(Error::Insert("x++;"))
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Final Conversion of code into an appropriate architecture
+
+use crate::intermediate::{BrainFuck, SpanTree};
+
+mod aarch64;
+mod bf;
+mod c99;
+mod elf;
+mod javascript;
+mod jvm;
+mod python;
+mod riscv64;
+mod rust;
+mod x86_kernel;
+
+pub use jvm::Error as ClassError;
+
+pub enum Backend {
+    C99,
+    Bf,
+    RiscV64,
+    AArch64,
+    X86Kernel,
+    Rust,
+    JavaScript,
+    Python,
+}
+
+/// The environment the compiled program runs in.
+pub struct Settings<'a> {
+    /// The number of cells before the initial pointer position.
+    pub pre: u32,
+    /// The number of cells after (and including) the initial pointer position.
+    pub post: u32,
+    /// Settings for running without an operating system, if enabled.
+    pub freestanding: Option<Freestanding>,
+    /// Whether to buffer output (flushing on newline, before input and at
+    /// exit), rather than writing each byte immediately.
+    pub buffered: bool,
+    /// The source of the program, for backends referring back to it.
+    pub source: Option<Source<'a>>,
+    /// The number of statements (and loop iterations) allowed before the
+    /// program stops, if limited (only supported by some backends).
+    pub max_steps: Option<u64>,
+}
+
+/// The source text of a program, with the spans of its (unoptimised)
+/// statements.
+pub struct Source<'a> {
+    pub text: &'a str,
+    pub spans: &'a [SpanTree],
+}
+
+/// Settings for bare-metal programs, started from `_start` without libc or
+/// syscalls.
+pub struct Freestanding {
+    pub output: Hook,
+    pub input: Hook,
+    /// A fixed address to place the tape at (otherwise it is placed in
+    /// `.bss`).
+    pub tape_address: Option<u64>,
+    /// The address the program is linked to run at.
+    pub load_address: u64,
+}
+
+/// How input and output are done in freestanding programs.
+pub enum Hook {
+    /// Call an external function taking (output) or returning (input) the
+    /// cell's value.
+    Extern(String),
+    /// Code placed in the output for every input or output, with the pointer
+    /// in the same place as for inserts.
+    Template(String),
+}
+
+impl Backend {
+    /// Check if the backend can produce freestanding programs.
+    pub fn supports_freestanding(&self) -> bool {
+        matches!(self, Backend::C99 | Backend::RiscV64 | Backend::AArch64)
+    }
+
+    /// Check if the backend can limit the steps run by programs.
+    pub fn supports_step_limit(&self) -> bool {
+        matches!(self, Backend::C99)
+    }
+}
+
+pub fn compile(backend: Backend, bf: &BrainFuck, settings: &Settings) -> (String, &'static str) {
+    let (compile_fn, ext): (fn(&BrainFuck, &Settings) -> String, _) = match backend {
+        Backend::C99 => (c99::compile, "c"),
+        Backend::Bf => (bf::compile, "min.bf"),
+        Backend::RiscV64 => (riscv64::compile, "s"),
+        Backend::AArch64 => (aarch64::compile, "s"),
+        Backend::X86Kernel => (x86_kernel::compile, "s"),
+        Backend::Rust => (rust::compile, "rs"),
+        Backend::JavaScript => (javascript::compile, "mjs"),
+        Backend::Python => (python::compile, "py"),
+    };
+    (compile_fn.call((bf, settings)), ext)
+}
+
+/// Get the linker script for a freestanding program. The program is placed at
+/// the load address, followed by a 16KiB stack (with the top at
+/// `__stack_top`).
+pub fn link_script(freestanding: &Freestanding) -> String {
+    format!(
+        "ENTRY(_start)\nSECTIONS\n{{\n\t. = {:#x};\n\t.text : {{ *(.text._start) *(.text*) }}\n\t.rodata : {{ *(.rodata*) }}\n\t.data : {{ *(.data*) }}\n\t.bss : {{ *(.bss*) *(COMMON) }}\n\t. = ALIGN(16);\n\t. += 0x4000;\n\t__stack_top = .;\n}}\n",
+        freestanding.load_address
+    )
+}
+
+/// Build a standalone x86-64 Linux executable, failing with the text of any
+/// insert in the program.
+pub fn build<'a>(bf: &'a BrainFuck, settings: &Settings) -> Result<Vec<u8>, &'a str> {
+    elf::build(bf, settings)
+}
+
+/// Build a JVM class (with the given name) running the program from its
+/// `main` method.
+pub fn build_class<'a>(
+    bf: &'a BrainFuck,
+    name: &str,
+    settings: &Settings,
+) -> Result<Vec<u8>, ClassError<'a>> {
+    jvm::build(bf, name, settings)
+}
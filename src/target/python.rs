@@ -0,0 +1,269 @@
+//! Transpilation to readable Python 3, for teaching.
+//!
+//! The tape is a `bytearray` and I/O uses `sys.stdin.buffer` and
+//! `sys.stdout.buffer`. When the source is available, the first statement
+//! from each source line is preceded by a comment with that line (statements
+//! combined by the optimiser can have several), so the program can be stepped
+//! through alongside the brainfuck. Inserts are placed as raw
+//! Python statements, with `cells` and `ptr` in scope.
+//!
+//! To run:
+//! ```text
+//! python3 prog.py
+//! ```
+
+use std::{
+    collections::HashSet,
+    fmt::{Formatter, FormattingOptions},
+};
+
+use super::{Settings, Source};
+use crate::intermediate::{BrainFuck, Op, SpanTree, Stat};
+
+const HEADER: &str = "import sys
+
+BUFFERED = {buffered}
+
+
+def output(cell):
+    \"\"\"Write a cell's value to stdout, flushing on newline if buffered.\"\"\"
+    sys.stdout.buffer.write(bytes([cell]))
+    if cell == 10 or not BUFFERED:
+        sys.stdout.buffer.flush()
+
+
+def write(data):
+    \"\"\"Write known bytes to stdout, flushing on newline if buffered.\"\"\"
+    sys.stdout.buffer.write(data)
+    if b\"\\n\" in data or not BUFFERED:
+        sys.stdout.buffer.flush()
+
+
+def read():
+    \"\"\"Read a byte from stdin, or 255 at the end of input.\"\"\"
+    sys.stdout.buffer.flush()
+    data = sys.stdin.buffer.read(1)
+    return data[0] if data else 255
+
+";
+
+/// Compile the brainfuck program, given pre bytes of memory before the
+/// starting position, and post bytes after.
+pub fn compile(BrainFuck(stats): &BrainFuck, settings: &Settings) -> String {
+    let Settings {
+        pre,
+        post,
+        source,
+        buffered,
+        ..
+    } = settings;
+    let mut result = String::new();
+    let mut f = Formatter::new(&mut result, FormattingOptions::new());
+
+    writeln!(
+        f,
+        "{}\ndef main():\n    cells = bytearray({})\n    ptr = {}",
+        HEADER.replace("{buffered}", if *buffered { "True" } else { "False" }),
+        pre + post,
+        pre
+    )
+    .expect("Writing to string");
+
+    let mut writer = Writer {
+        source: source.as_ref(),
+        commented: HashSet::new(),
+    };
+    writer
+        .stats(stats, source.as_ref().map(|source| source.spans), 1, &mut f)
+        .expect("Writing to string");
+
+    writeln!(
+        f,
+        "    sys.stdout.buffer.flush()\n\n\nif __name__ == \"__main__\":\n    main()"
+    )
+    .expect("Writing to string");
+
+    result
+}
+
+/// Writes statements, tracking the source lines already commented.
+struct Writer<'a> {
+    source: Option<&'a Source<'a>>,
+    commented: HashSet<usize>,
+}
+
+impl Writer<'_> {
+    fn stats(
+        &mut self,
+        stats: &[Stat],
+        spans: Option<&[SpanTree]>,
+        indent_lvl: usize,
+        f: &mut Formatter<'_>,
+    ) -> std::fmt::Result {
+        for (i, stat) in stats.iter().enumerate() {
+            self.stat(stat, spans.and_then(|spans| spans.get(i)), indent_lvl, f)?;
+        }
+        Ok(())
+    }
+
+    /// Comment the source lines of a statement (only the first line of a
+    /// loop, as its body comments the rest), if not already commented.
+    fn comment(
+        &mut self,
+        stat: &Stat,
+        span: Option<&SpanTree>,
+        indent: &str,
+        f: &mut Formatter<'_>,
+    ) -> std::fmt::Result {
+        if let (Some(source), Some(SpanTree(span, _))) = (self.source, span) {
+            let (first, _) = span.location(source.text);
+            // optimised statements can span several lines
+            let last = match stat {
+                Stat::WhileNonZero(_) => first,
+                _ => {
+                    first
+                        + source.text[span.start..span.end]
+                            .trim_end()
+                            .matches('\n')
+                            .count()
+                }
+            };
+            for line in first..=last {
+                if self.commented.insert(line) {
+                    let text = source.text.lines().nth(line - 1).unwrap_or("").trim();
+                    writeln!(f, "{}# line {}: {}", indent, line, text)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stat(
+        &mut self,
+        stat: &Stat,
+        span: Option<&SpanTree>,
+        indent_lvl: usize,
+        f: &mut Formatter<'_>,
+    ) -> std::fmt::Result {
+        let indent = "    ".repeat(indent_lvl);
+        self.comment(stat, span, &indent, f)?;
+        write!(f, "{}", indent)?;
+        match stat {
+            Stat::PtrMove(i @ i32::MIN..=-1) => writeln!(f, "ptr -= {}", -(*i as i64)),
+            Stat::PtrMove(i) => writeln!(f, "ptr += {}", i),
+            Stat::DerefOp(Op::Add, i) => {
+                writeln!(f, "cells[ptr] = (cells[ptr] + {}) % 256", i.rem_euclid(256))
+            }
+            Stat::DerefOp(Op::Mul, i) => {
+                writeln!(f, "cells[ptr] = (cells[ptr] * {}) % 256", i.rem_euclid(256))
+            }
+            Stat::DerefOp(Op::Div | Op::Mod, i) if i.rem_euclid(256) == 0 => {
+                writeln!(f, "cells[ptr] = 0")
+            }
+            Stat::DerefOp(op, i) => writeln!(
+                f,
+                "cells[ptr] {}= {}",
+                if *op == Op::Div { "//" } else { "%" },
+                i.rem_euclid(256)
+            ),
+            Stat::MulAdd(offset, factor) => writeln!(
+                f,
+                "if cells[ptr] != 0:\n{2}    cells[ptr + {0}] = (cells[ptr + {0}] + cells[ptr] * {1}) % 256",
+                offset,
+                factor.rem_euclid(256),
+                indent
+            ),
+            Stat::SetCells(offset, cells) => writeln!(
+                f,
+                "cells[ptr + {}:ptr + {}] = bytes({:?})",
+                offset,
+                offset + cells.len() as i32,
+                cells
+            ),
+            Stat::Output => writeln!(f, "output(cells[ptr])"),
+            Stat::OutputConst(bytes) => writeln!(f, "write(b\"{}\")", bytes.escape_ascii()),
+            Stat::Input => writeln!(f, "cells[ptr] = read()"),
+            Stat::ScanZero(1) => writeln!(f, "ptr = cells.index(0, ptr)"),
+            Stat::ScanZero(-1) => writeln!(f, "ptr = cells.rindex(0, 0, ptr + 1)"),
+            Stat::ScanZero(stride) => {
+                writeln!(f, "while cells[ptr] != 0:\n{}    ptr += {}", indent, stride)
+            }
+            Stat::WhileNonZero(stats) => {
+                writeln!(f, "while cells[ptr] != 0:")?;
+                if stats.is_empty() {
+                    writeln!(f, "{}    pass", indent)?;
+                }
+                self.stats(
+                    stats,
+                    span.map(|SpanTree(_, spans)| &spans[..]),
+                    indent_lvl + 1,
+                    f,
+                )
+            }
+            Stat::Asm(asm) => {
+                writeln!(f, "# Start of inserted section")?;
+                for line in asm.lines() {
+                    writeln!(f, "{}{}", indent, line.trim())?;
+                }
+                writeln!(f, "{}# End of inserted section", indent)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimiser::optimise, parser::parse_spanned};
+
+    #[test]
+    fn comments_source_lines() {
+        let text = "+\n[->+<]\n.";
+        let (bf, spans) = parse_spanned(text).unwrap();
+        let settings = Settings {
+            pre: 0,
+            post: 10,
+            freestanding: None,
+            buffered: true,
+            source: Some(Source {
+                text,
+                spans: &spans,
+            }),
+            max_steps: None,
+        };
+        let result = compile(&bf, &settings);
+        let body = &result
+            [result.find("    ptr = 0\n").unwrap() + 12..result.rfind("    sys.stdout").unwrap()];
+        assert_eq!(
+            body,
+            "    # line 1: +\n    cells[ptr] = (cells[ptr] + 1) % 256\n\
+             \x20   # line 2: [->+<]\n    while cells[ptr] != 0:\n\
+             \x20       cells[ptr] = (cells[ptr] + 255) % 256\n\
+             \x20       ptr += 1\n\
+             \x20       cells[ptr] = (cells[ptr] + 1) % 256\n\
+             \x20       ptr -= 1\n\
+             \x20   # line 3: .\n    output(cells[ptr])\n"
+        );
+    }
+
+    #[test]
+    fn comments_each_line_once() {
+        let text = ",[->+<]>[-<+>]<.";
+        let (bf, spans) = parse_spanned(text).unwrap();
+        let (bf, spans) = optimise(bf, spans, Some((0, 10)), &[], &mut |_, _, _| ());
+        let settings = Settings {
+            pre: 0,
+            post: 10,
+            freestanding: None,
+            buffered: true,
+            source: Some(Source {
+                text,
+                spans: &spans,
+            }),
+            max_steps: None,
+        };
+        let result = compile(&bf, &settings);
+        assert_eq!(result.matches("# line 1: ").count(), 1);
+        assert!(result.contains("cells[ptr + 1] = (cells[ptr + 1] + cells[ptr] * 1) % 256"));
+    }
+}
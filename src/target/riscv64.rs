@@ -0,0 +1,317 @@
+//! Compilation to RISC-V (RV64I) assembly for Linux, in GAS syntax.
+//!
+//! Only the base integer instruction set is used (multiplication is done with
+//! shifts and adds, division by repeated subtraction). The tape is placed in
+//! `.bss`, `s1` holds the pointer and I/O uses `read`/`write` syscalls (with
+//! output buffered unless disabled).
+//!
+//! To assemble, link and run:
+//! ```text
+//! riscv64-linux-gnu-as prog.s -o prog.o && riscv64-linux-gnu-ld prog.o -o prog && qemu-riscv64 prog
+//! ```
+//!
+//! Freestanding programs set the stack from `__stack_top`, and halt with `wfi`
+//! once finished. With I/O hooks provided in `io.s`:
+//! ```text
+//! riscv64-linux-gnu-as prog.s io.s -o prog.o && riscv64-linux-gnu-ld -T prog.ld prog.o -o prog
+//! qemu-system-riscv64 -machine virt -bios none -nographic -kernel prog
+//! ```
+
+use std::fmt::{Formatter, FormattingOptions};
+
+use super::{Freestanding, Hook, Settings};
+use crate::intermediate::{BrainFuck, Op, Stat};
+
+/// Output buffering routines: `.Lput` adds the byte in `a0` to the buffer,
+/// flushing on a newline or when full, and `.Lflush` writes the buffer.
+const BUFFER: &str = ".Lput:
+	la t0, outlen
+	ld t1, 0(t0)
+	la t2, outbuf
+	add t2, t2, t1
+	sb a0, 0(t2)
+	addi t1, t1, 1
+	sd t1, 0(t0)
+	li t2, 10
+	beq a0, t2, .Lflush
+	li t2, 4096
+	beq t1, t2, .Lflush
+	ret
+.Lflush:
+	la t0, outlen
+	ld a2, 0(t0)
+	sd zero, 0(t0)
+	li a7, 64
+	li a0, 1
+	la a1, outbuf
+	ecall
+	ret
+
+	.section .bss
+outlen:
+	.zero 8
+outbuf:
+	.zero 4096";
+
+/// Compile the brainfuck program, given pre bytes of memory before the
+/// starting position, and post bytes after.
+pub fn compile(BrainFuck(stats): &BrainFuck, settings: &Settings) -> String {
+    let Settings {
+        pre,
+        post,
+        freestanding,
+        buffered,
+        ..
+    } = settings;
+    // freestanding programs use the hooks for output, so are not buffered
+    let buffered = *buffered && freestanding.is_none();
+    let mut result = String::new();
+    let mut f = Formatter::new(&mut result, FormattingOptions::new());
+
+    match freestanding {
+        None => writeln!(f, "\t.section .text\n\t.globl _start\n_start:"),
+        Some(_) => writeln!(
+            f,
+            "\t.section .text._start\n\t.globl _start\n_start:\n\tla sp, __stack_top"
+        ),
+    }
+    .expect("Writing to string");
+
+    match freestanding.as_ref().and_then(|freestanding| freestanding.tape_address) {
+        None => writeln!(
+            f,
+            "\tla s1, cells + {}\n\n\t.section .bss\ncells:\n\t.zero {}\n\t.section .text",
+            pre,
+            pre + post
+        ),
+        Some(address) => writeln!(
+            f,
+            "\tli s1, {:#x}\n\tli t0, {}\n\tmv t1, s1\n.Lzero:\n\tbeqz t0, .Lzeroend\n\tsb zero, 0(t1)\n\taddi t1, t1, 1\n\taddi t0, t0, -1\n\tj .Lzero\n.Lzeroend:\n\tli t0, {}\n\tadd s1, s1, t0",
+            address,
+            pre + post,
+            pre
+        ),
+    }
+    .expect("Writing to string");
+
+    let mut labels = 0;
+    for stat in stats {
+        transpile_stat(stat, &mut labels, freestanding.as_ref(), buffered, &mut f)
+            .expect("Writing to string");
+    }
+
+    match freestanding {
+        None if buffered => writeln!(
+            f,
+            "\tcall .Lflush\n\tli a7, 93\n\tli a0, 0\n\tecall\n\n{}",
+            BUFFER
+        ),
+        None => writeln!(f, "\tli a7, 93\n\tli a0, 0\n\tecall"),
+        Some(_) => writeln!(f, ".Lhalt:\n\twfi\n\tj .Lhalt"),
+    }
+    .expect("Writing to string");
+
+    result
+}
+
+/// Check if an immediate fits in the 12 bit signed immediate of I/S type
+/// instructions.
+fn is_imm12(i: i32) -> bool {
+    (-2048..2048).contains(&i)
+}
+
+/// Write a syscall on the current cell (read or write, with one byte).
+fn syscall(number: u8, fd: u8, f: &mut Formatter<'_>) -> std::fmt::Result {
+    writeln!(
+        f,
+        "\tli a7, {}\n\tli a0, {}\n\tmv a1, s1\n\tli a2, 1\n\tecall",
+        number, fd
+    )
+}
+
+/// Write bytes to `.rodata` at a label.
+fn rodata(label: usize, bytes: &[u8], f: &mut Formatter<'_>) -> std::fmt::Result {
+    writeln!(
+        f,
+        "\t.pushsection .rodata\n.Lstr{}:\n\t.byte {}\n\t.popsection",
+        label,
+        bytes
+            .iter()
+            .map(|byte| byte.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Write inserted assembly (an insert or I/O template), one instruction per
+/// line.
+fn insert(asm: &str, f: &mut Formatter<'_>) -> std::fmt::Result {
+    for line in asm.lines() {
+        writeln!(f, "\t{}", line.trim())?;
+    }
+    Ok(())
+}
+
+fn transpile_stat(
+    stat: &Stat,
+    labels: &mut usize,
+    freestanding: Option<&Freestanding>,
+    buffered: bool,
+    f: &mut Formatter<'_>,
+) -> std::fmt::Result {
+    match stat {
+        Stat::PtrMove(0) => Ok(()),
+        Stat::PtrMove(i) if is_imm12(*i) => writeln!(f, "\taddi s1, s1, {}", i),
+        Stat::PtrMove(i) => writeln!(f, "\tli t0, {}\n\tadd s1, s1, t0", i),
+        Stat::DerefOp(Op::Add, i) => writeln!(
+            f,
+            "\tlbu t0, 0(s1)\n\taddi t0, t0, {}\n\tsb t0, 0(s1)",
+            i.rem_euclid(256) as u8 as i8
+        ),
+        Stat::DerefOp(Op::Mul, i) => {
+            // sum the shifted cell for each bit set in the multiplier
+            writeln!(f, "\tlbu t0, 0(s1)\n\tli t1, 0")?;
+            let i = i.rem_euclid(256);
+            for bit in (0..8).filter(|bit| i & (1 << bit) != 0) {
+                writeln!(f, "\tslli t2, t0, {}\n\tadd t1, t1, t2", bit)?;
+            }
+            writeln!(f, "\tsb t1, 0(s1)")
+        }
+        Stat::MulAdd(offset, factor) => {
+            // as for multiplication, then add to the target cell (skipped if
+            // the current cell is zero)
+            let label = *labels;
+            *labels += 1;
+            writeln!(f, "\tlbu t0, 0(s1)\n\tbeqz t0, .Lskip{}", label)?;
+            let address = if is_imm12(*offset) {
+                format!("{}(s1)", offset)
+            } else {
+                writeln!(f, "\tli t3, {}\n\tadd t3, s1, t3", offset)?;
+                "0(t3)".to_string()
+            };
+            writeln!(f, "\tlbu t1, {}", address)?;
+            let factor = factor.rem_euclid(256);
+            for bit in (0..8).filter(|bit| factor & (1 << bit) != 0) {
+                writeln!(f, "\tslli t2, t0, {}\n\tadd t1, t1, t2", bit)?;
+            }
+            writeln!(f, "\tsb t1, {}\n.Lskip{}:", address, label)
+        }
+        Stat::DerefOp(Op::Div | Op::Mod, i) if i.rem_euclid(256) == 0 => {
+            writeln!(f, "\tsb zero, 0(s1)")
+        }
+        Stat::DerefOp(op, i) => {
+            // subtract the divisor until the remainder is less than it
+            let label = *labels;
+            *labels += 1;
+            writeln!(
+                f,
+                "\tlbu t0, 0(s1)\n\tli t1, 0\n\tli t2, {}\n.Ldiv{}:\n\tbltu t0, t2, .Ldivend{}\n\tsub t0, t0, t2\n\taddi t1, t1, 1\n\tj .Ldiv{}\n.Ldivend{}:\n\tsb {}, 0(s1)",
+                i.rem_euclid(256),
+                label,
+                label,
+                label,
+                label,
+                if *op == Op::Div { "t1" } else { "t0" }
+            )
+        }
+        Stat::Output => match freestanding.map(|freestanding| &freestanding.output) {
+            None if buffered => writeln!(f, "\tlbu a0, 0(s1)\n\tcall .Lput"),
+            None => syscall(64, 1, f),
+            Some(Hook::Extern(name)) => writeln!(f, "\tlbu a0, 0(s1)\n\tcall {}", name),
+            Some(Hook::Template(asm)) => insert(asm, f),
+        },
+        Stat::OutputConst(bytes) => match freestanding.map(|freestanding| &freestanding.output) {
+            None => {
+                let label = *labels;
+                *labels += 1;
+                rodata(label, bytes, f)?;
+                if buffered {
+                    writeln!(f, "\tcall .Lflush")?;
+                }
+                writeln!(
+                    f,
+                    "\tli a7, 64\n\tli a0, 1\n\tla a1, .Lstr{}\n\tli a2, {}\n\tecall",
+                    label,
+                    bytes.len()
+                )
+            }
+            Some(Hook::Extern(name)) => {
+                for byte in bytes {
+                    writeln!(f, "\tli a0, {}\n\tcall {}", byte, name)?;
+                }
+                Ok(())
+            }
+            Some(Hook::Template(asm)) => {
+                // point s1 at each byte in turn, as the template outputs the
+                // current cell
+                let label = *labels;
+                *labels += 1;
+                rodata(label, bytes, f)?;
+                writeln!(
+                    f,
+                    "\taddi sp, sp, -16\n\tsd s1, 0(sp)\n\tla s1, .Lstr{}",
+                    label
+                )?;
+                for _ in bytes {
+                    insert(asm, f)?;
+                    writeln!(f, "\taddi s1, s1, 1")?;
+                }
+                writeln!(f, "\tld s1, 0(sp)\n\taddi sp, sp, 16")
+            }
+        },
+        Stat::Input => match freestanding.map(|freestanding| &freestanding.input) {
+            None => {
+                if buffered {
+                    writeln!(f, "\tcall .Lflush")?;
+                }
+                writeln!(f, "\tli t0, 255\n\tsb t0, 0(s1)")?;
+                syscall(63, 0, f)
+            }
+            Some(Hook::Extern(name)) => writeln!(f, "\tcall {}\n\tsb a0, 0(s1)", name),
+            Some(Hook::Template(asm)) => insert(asm, f),
+        },
+        Stat::ScanZero(stride) => {
+            // RV64I has no vector instructions, so check a cell at a time
+            let label = *labels;
+            *labels += 1;
+            writeln!(
+                f,
+                ".Lscan{}:\n\tlbu t0, 0(s1)\n\tbeqz t0, .Lscanend{}",
+                label, label
+            )?;
+            transpile_stat(&Stat::PtrMove(*stride), labels, freestanding, buffered, f)?;
+            writeln!(f, "\tj .Lscan{}\n.Lscanend{}:", label, label)
+        }
+        Stat::WhileNonZero(stats) => {
+            let label = *labels;
+            *labels += 1;
+            // conditional branches have a short range, so jump to the end
+            writeln!(
+                f,
+                ".Lloop{}:\n\tlbu t0, 0(s1)\n\tbnez t0, .Lbody{}\n\tj .Lend{}\n.Lbody{}:",
+                label, label, label, label
+            )?;
+            for stat in stats {
+                transpile_stat(stat, labels, freestanding, buffered, f)?;
+            }
+            writeln!(f, "\tj .Lloop{}\n.Lend{}:", label, label)
+        }
+        Stat::SetCells(offset, cells) => {
+            for (i, cell) in cells.iter().enumerate() {
+                let offset = offset + i as i32;
+                writeln!(f, "\tli t0, {}", cell)?;
+                if is_imm12(offset) {
+                    writeln!(f, "\tsb t0, {}(s1)", offset)?;
+                } else {
+                    writeln!(f, "\tli t1, {}\n\tadd t1, s1, t1\n\tsb t0, 0(t1)", offset)?;
+                }
+            }
+            Ok(())
+        }
+        Stat::Asm(asm) => {
+            writeln!(f, "\t# Start of inserted section")?;
+            insert(asm, f)?;
+            writeln!(f, "\t# End of inserted section")
+        }
+    }
+}
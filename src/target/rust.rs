@@ -0,0 +1,181 @@
+//! Transpilation to a self-contained Rust program.
+//!
+//! The tape is a `Vec<u8>` (so out of bounds accesses panic rather than
+//! corrupting memory). Output is buffered (unless disabled), and flushed on
+//! newline, before input and at exit.
+//! Inserts are placed as raw Rust statements, with `cells`, `ptr`, `input`
+//! and `output` in scope.
+//!
+//! To build and run:
+//! ```text
+//! rustc -O prog.rs && ./prog
+//! ```
+
+use std::fmt::{Formatter, FormattingOptions};
+
+use super::Settings;
+use crate::intermediate::{BrainFuck, Op, Stat};
+
+/// Compile the brainfuck program, given pre bytes of memory before the
+/// starting position, and post bytes after.
+pub fn compile(BrainFuck(stats): &BrainFuck, settings: &Settings) -> String {
+    let Settings {
+        pre,
+        post,
+        buffered,
+        ..
+    } = settings;
+    let mut result = String::new();
+    let mut f = Formatter::new(&mut result, FormattingOptions::new());
+
+    // the pointer can be constant, so guarded accesses off the tape must not
+    // fail to compile
+    writeln!(
+        f,
+        "#![allow(unused_mut, unused_variables, arithmetic_overflow)]\nuse std::io::{{LineWriter, Read, Write}};\n\nfn main() {{\n    let mut cells = vec![0u8; {}];\n    let mut ptr: usize = {};\n    let mut input = std::io::stdin().lock().bytes();\n    let mut output = LineWriter::new(std::io::stdout().lock());",
+        pre + post,
+        pre
+    )
+    .expect("Writing to string");
+
+    for stat in stats {
+        transpile_stat(stat, 1, *buffered, &mut f).expect("Writing to string");
+    }
+
+    writeln!(f, "    output.flush().unwrap();\n}}").expect("Writing to string");
+
+    result
+}
+
+/// The index of a cell at an offset from the pointer.
+fn index(offset: i32) -> String {
+    match offset {
+        0 => "ptr".to_string(),
+        1..=i32::MAX => format!("ptr + {}", offset),
+        _ => format!("ptr - {}", -(offset as i64)),
+    }
+}
+
+fn transpile_stat(
+    stat: &Stat,
+    indent_lvl: usize,
+    buffered: bool,
+    f: &mut Formatter<'_>,
+) -> std::fmt::Result {
+    let indent = "    ".repeat(indent_lvl);
+    write!(f, "{}", indent)?;
+    match stat {
+        Stat::PtrMove(i @ i32::MIN..=-1) => writeln!(f, "ptr -= {};", -(*i as i64)),
+        Stat::PtrMove(i @ 1..=i32::MAX) => writeln!(f, "ptr += {};", i),
+        Stat::PtrMove(0) => writeln!(f, "// redundant ptr move"),
+        Stat::DerefOp(Op::Add, i) => writeln!(
+            f,
+            "cells[ptr] = cells[ptr].wrapping_add({});",
+            i.rem_euclid(256)
+        ),
+        Stat::DerefOp(Op::Mul, i) => writeln!(
+            f,
+            "cells[ptr] = cells[ptr].wrapping_mul({});",
+            i.rem_euclid(256)
+        ),
+        Stat::DerefOp(Op::Div | Op::Mod, i) if i.rem_euclid(256) == 0 => {
+            writeln!(f, "cells[ptr] = 0;")
+        }
+        Stat::DerefOp(op, i) => writeln!(
+            f,
+            "cells[ptr] {}= {};",
+            if *op == Op::Div { "/" } else { "%" },
+            i.rem_euclid(256)
+        ),
+        Stat::MulAdd(offset, factor) => writeln!(
+            f,
+            "if cells[ptr] != 0 {{\n{2}    cells[{0}] = cells[{0}].wrapping_add(cells[ptr].wrapping_mul({1}));\n{2}}}",
+            index(*offset),
+            factor.rem_euclid(256),
+            indent
+        ),
+        Stat::SetCells(offset, cells) => writeln!(
+            f,
+            "cells[{}..{} + {}].copy_from_slice(&{:?});",
+            index(*offset),
+            index(*offset),
+            cells.len(),
+            cells
+        ),
+        Stat::Output if buffered => writeln!(f, "output.write_all(&[cells[ptr]]).unwrap();"),
+        Stat::Output => writeln!(
+            f,
+            "output.write_all(&[cells[ptr]]).unwrap();\n{}output.flush().unwrap();",
+            indent
+        ),
+        Stat::OutputConst(bytes) if buffered => {
+            writeln!(f, "output.write_all(b\"{}\").unwrap();", bytes.escape_ascii())
+        }
+        Stat::OutputConst(bytes) => writeln!(
+            f,
+            "output.write_all(b\"{}\").unwrap();\n{}output.flush().unwrap();",
+            bytes.escape_ascii(),
+            indent
+        ),
+        Stat::Input => writeln!(
+            f,
+            "output.flush().unwrap();\n{}cells[ptr] = input.next().and_then(Result::ok).unwrap_or(255);",
+            indent
+        ),
+        Stat::ScanZero(1) => writeln!(
+            f,
+            "ptr += cells[ptr..].iter().position(|cell| *cell == 0).unwrap();"
+        ),
+        Stat::ScanZero(-1) => writeln!(
+            f,
+            "ptr = cells[..=ptr].iter().rposition(|cell| *cell == 0).unwrap();"
+        ),
+        Stat::ScanZero(stride) => writeln!(
+            f,
+            "while cells[ptr] != 0 {{\n{}    ptr {}= {};\n{}}}",
+            indent,
+            if *stride < 0 { "-" } else { "+" },
+            stride.unsigned_abs(),
+            indent
+        ),
+        Stat::WhileNonZero(stats) => {
+            writeln!(f, "while cells[ptr] != 0 {{")?;
+            for stat in stats {
+                transpile_stat(stat, indent_lvl + 1, buffered, f)?;
+            }
+            writeln!(f, "{}}}", indent)
+        }
+        Stat::Asm(asm) => {
+            writeln!(f, "// Start of inserted section")?;
+            for line in asm.lines() {
+                writeln!(f, "{}{}", indent, line.trim())?;
+            }
+            writeln!(f, "{}// End of inserted section", indent)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn indents_loops() {
+        let BrainFuck(stats) = parse("[->+<]").unwrap();
+        let mut result = String::new();
+        let mut f = Formatter::new(&mut result, FormattingOptions::new());
+        for stat in &stats {
+            transpile_stat(stat, 1, true, &mut f).unwrap();
+        }
+        assert_eq!(
+            result,
+            "    while cells[ptr] != 0 {\n        \
+             cells[ptr] = cells[ptr].wrapping_add(255);\n        \
+             ptr += 1;\n        \
+             cells[ptr] = cells[ptr].wrapping_add(1);\n        \
+             ptr -= 1;\n    \
+             }\n"
+        );
+    }
+}
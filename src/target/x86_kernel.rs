@@ -0,0 +1,224 @@
+//! Compilation to a 32 bit x86 Multiboot kernel, in GAS (Intel syntax)
+//! assembly.
+//!
+//! The program is wrapped in a Multiboot header, so can be loaded directly by
+//! a Multiboot bootloader (e.g GRUB or qemu). Output is written to the VGA
+//! text buffer (scrolling when full), and input polls the PS/2 keyboard
+//! controller, translating scancodes (set 1, unshifted) to ASCII. Inserts are
+//! placed as-is, allowing access to ports and interrupts.
+//!
+//! `ebx` holds the pointer, all other registers are free for use by inserts.
+//!
+//! To assemble, link and run:
+//! ```text
+//! as --32 prog.s -o prog.o && ld -m elf_i386 -n -Ttext 0x100000 prog.o -o prog
+//! qemu-system-i386 -kernel prog
+//! ```
+
+use std::fmt::{Formatter, FormattingOptions};
+
+use super::Settings;
+use crate::intermediate::{BrainFuck, Op, Stat};
+
+const MULTIBOOT_MAGIC: u32 = 0x1BADB002;
+const VGA_ADDR: u32 = 0xB8000;
+const VGA_WIDTH: u32 = 80;
+const VGA_HEIGHT: u32 = 25;
+/// A space, in light grey on black.
+const VGA_BLANK: u32 = 0x0720;
+
+/// ASCII characters for scancodes (set 1) of key presses, with 0 for keys
+/// without a character.
+const SCANCODES: &[u8; 58] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+
+/// Write a character from `al` at the cursor (in `vga_cursor`), moving to
+/// the next line on a newline and scrolling when past the end of the screen.
+const VGA_PUTCHAR: &str = "vga_putchar:
+	pushad
+	mov edi, [vga_cursor]
+	cmp al, 10
+	je .Lnewline
+	mov ah, 0x07
+	mov [edi], ax
+	add edi, 2
+	jmp .Lscroll
+.Lnewline:
+	mov eax, edi
+	sub eax, {vga}
+	xor edx, edx
+	mov ecx, {row}
+	div ecx
+	sub edi, edx
+	add edi, {row}
+.Lscroll:
+	cmp edi, {vga} + {screen}
+	jb .Lputdone
+	mov esi, {vga} + {row}
+	mov edi, {vga}
+	mov ecx, ({screen} - {row}) / 4
+	rep movsd
+	mov eax, {blank} * 0x10001
+	mov ecx, {row} / 4
+	rep stosd
+	mov edi, {vga} + {screen} - {row}
+.Lputdone:
+	mov [vga_cursor], edi
+	popad
+	ret";
+
+/// Wait for a key press, returning its character in `al`.
+const KBD_GETCHAR: &str = "kbd_getchar:
+	in al, 0x64
+	test al, 1
+	jz kbd_getchar
+	in al, 0x60
+	cmp al, {keys}
+	jae kbd_getchar
+	movzx eax, al
+	mov al, [scancodes + eax]
+	test al, al
+	jz kbd_getchar
+	ret";
+
+/// Compile the brainfuck program to a kernel, given pre bytes of memory
+/// before the starting position, and post bytes after.
+pub fn compile(BrainFuck(stats): &BrainFuck, settings: &Settings) -> String {
+    let Settings { pre, post, .. } = settings;
+    let mut result = String::new();
+    let mut f = Formatter::new(&mut result, FormattingOptions::new());
+
+    // the header must be within the first 8KiB, so is placed at the start
+    // of the text section
+    writeln!(
+        f,
+        "\t.intel_syntax noprefix\n\n\t.section .text\n\t.align 4\n\t.long {:#x}\n\t.long 0\n\t.long -{:#x}\n\n\t.globl _start\n_start:\n\tmov esp, offset stack_top\n\tcld\n\tmov edi, offset cells\n\tmov ecx, {}\n\txor eax, eax\n\trep stosb\n\tmov edi, {:#x}\n\tmov ecx, {}\n\tmov ax, {:#x}\n\trep stosw\n\tmov ebx, offset cells + {}",
+        MULTIBOOT_MAGIC,
+        MULTIBOOT_MAGIC,
+        pre + post,
+        VGA_ADDR,
+        VGA_WIDTH * VGA_HEIGHT,
+        VGA_BLANK,
+        pre
+    )
+    .expect("Writing to string");
+
+    let mut labels = 0;
+    for stat in stats {
+        transpile_stat(stat, &mut labels, &mut f).expect("Writing to string");
+    }
+
+    let row = VGA_WIDTH * 2;
+    writeln!(
+        f,
+        ".Lhalt:\n\tcli\n\thlt\n\tjmp .Lhalt\n\n{}\n\n{}\n\n\t.section .data\nvga_cursor:\n\t.long {:#x}\nscancodes:\n\t.byte {}\n\n\t.section .bss\n\t.align 16\n\t.skip 16384\nstack_top:\ncells:\n\t.skip {}",
+        VGA_PUTCHAR
+            .replace("{vga}", &format!("{:#x}", VGA_ADDR))
+            .replace("{row}", &row.to_string())
+            .replace("{screen}", &(row * VGA_HEIGHT).to_string())
+            .replace("{blank}", &format!("{:#x}", VGA_BLANK)),
+        KBD_GETCHAR.replace("{keys}", &SCANCODES.len().to_string()),
+        VGA_ADDR,
+        SCANCODES
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        pre + post
+    )
+    .expect("Writing to string");
+
+    result
+}
+
+fn transpile_stat(stat: &Stat, labels: &mut usize, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match stat {
+        Stat::PtrMove(0) => Ok(()),
+        Stat::PtrMove(i) => writeln!(f, "\tadd ebx, {}", i),
+        Stat::DerefOp(Op::Add, i) => {
+            writeln!(f, "\tadd byte ptr [ebx], {}", i.rem_euclid(256))
+        }
+        Stat::DerefOp(Op::Mul, i) => writeln!(
+            f,
+            "\tmovzx eax, byte ptr [ebx]\n\timul eax, eax, {}\n\tmov [ebx], al",
+            i.rem_euclid(256)
+        ),
+        Stat::MulAdd(offset, factor) => {
+            let label = *labels;
+            *labels += 1;
+            writeln!(
+                f,
+                "\tmovzx eax, byte ptr [ebx]\n\ttest eax, eax\n\tje .Lskip{}\n\timul eax, eax, {}\n\tadd [ebx + {}], al\n.Lskip{}:",
+                label,
+                factor.rem_euclid(256),
+                offset,
+                label
+            )
+        }
+        Stat::DerefOp(Op::Div | Op::Mod, i) if i.rem_euclid(256) == 0 => {
+            writeln!(f, "\tmov byte ptr [ebx], 0")
+        }
+        Stat::DerefOp(op, i) => writeln!(
+            f,
+            "\tmovzx eax, byte ptr [ebx]\n\txor edx, edx\n\tmov ecx, {}\n\tdiv ecx\n\tmov [ebx], {}",
+            i.rem_euclid(256),
+            if *op == Op::Div { "al" } else { "dl" }
+        ),
+        Stat::Output => writeln!(f, "\tmov al, [ebx]\n\tcall vga_putchar"),
+        Stat::OutputConst(bytes) => {
+            for byte in bytes {
+                writeln!(f, "\tmov al, {}\n\tcall vga_putchar", byte)?;
+            }
+            Ok(())
+        }
+        Stat::Input => writeln!(f, "\tcall kbd_getchar\n\tmov [ebx], al"),
+        Stat::ScanZero(stride) => {
+            let label = *labels;
+            *labels += 1;
+            writeln!(
+                f,
+                ".Lscan{}:\n\tcmp byte ptr [ebx], 0\n\tje .Lscanend{}\n\tadd ebx, {}\n\tjmp .Lscan{}\n.Lscanend{}:",
+                label, label, stride, label, label
+            )
+        }
+        Stat::WhileNonZero(stats) => {
+            let label = *labels;
+            *labels += 1;
+            writeln!(
+                f,
+                ".Lloop{}:\n\tcmp byte ptr [ebx], 0\n\tje .Lend{}",
+                label, label
+            )?;
+            for stat in stats {
+                transpile_stat(stat, labels, f)?;
+            }
+            writeln!(f, "\tjmp .Lloop{}\n.Lend{}:", label, label)
+        }
+        Stat::SetCells(offset, cells) => {
+            for (i, cell) in cells.iter().enumerate() {
+                writeln!(f, "\tmov byte ptr [ebx + {}], {}", offset + i as i32, cell)?;
+            }
+            Ok(())
+        }
+        Stat::Asm(asm) => {
+            writeln!(f, "\t# Start of inserted section")?;
+            for line in asm.lines() {
+                writeln!(f, "\t{}", line.trim())?;
+            }
+            writeln!(f, "\t# End of inserted section")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_scancodes() {
+        // 'a', enter and space in scancode set 1
+        assert_eq!(SCANCODES[0x1E], b'a');
+        assert_eq!(SCANCODES[0x1C], b'\n');
+        assert_eq!(SCANCODES[0x39], b' ');
+    }
+}